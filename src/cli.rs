@@ -1,5 +1,9 @@
-use crate::graph::{ComponentRef, Edge, FileRef, Graph};
-use std::collections::HashMap;
+use cpdep::diagnostics::{Category, Diagnostic};
+use cpdep::error::{Context, Result};
+use cpdep::graph::{ComponentRef, Edge, FileRef, Graph};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 
 pub fn print_components(
     graph: &Graph,
@@ -7,13 +11,17 @@ pub fn print_components(
     component_to: Option<String>,
     verbose: bool,
     only_public: bool,
-) {
+) -> Result<()> {
+    for name in component_from.iter().chain(&component_to) {
+        graph.component_name_to_ref(name)?;
+    }
     for (c_ref, c) in graph.components.iter().enumerate() {
         let c_name = c.nice_name();
         if component_from.as_ref().map(|f| f == c_name).unwrap_or(true) {
-            print_component(&graph, c_ref, &component_to, verbose, only_public);
+            print_component(graph, c_ref, &component_to, verbose, only_public);
         }
     }
+    Ok(())
 }
 
 fn print_component(
@@ -29,23 +37,19 @@ fn print_component(
         graph.component_files[c].len()
     );
 
-    let (dep_in, dep_out) = graph.linked_components(c, only_public);
+    let links = graph.component_links(c, only_public);
 
-    let print_deps = |deps: HashMap<ComponentRef, Vec<Edge>>| {
-        let mut sorted_keys: Vec<ComponentRef> = deps.keys().cloned().collect();
-        let sort_fn = |a: &ComponentRef, b: &ComponentRef| {
-            graph.components[*a].path.cmp(&graph.components[*b].path)
-        };
-        sorted_keys.sort_by(sort_fn);
-        for c_ref in sorted_keys {
-            let name = graph.components[c_ref].nice_name();
+    let print_deps = |deps: &[(ComponentRef, Vec<Edge>)]| {
+        for (c_ref, edges) in deps {
+            let name = graph.components[*c_ref].nice_name();
             if component_to.as_ref().map(|t| t == name).unwrap_or(true) {
                 println!("    {}", name);
                 if verbose {
-                    for e in &deps[&c_ref] {
+                    for e in edges {
                         println!(
                             "      {} -> {}",
-                            graph.files[e.from].path, graph.files[e.to].path
+                            graph.edge_location(e),
+                            graph.files[e.to].path
                         );
                     }
                 }
@@ -54,84 +58,120 @@ fn print_component(
     };
 
     println!("  Incoming:");
-    print_deps(dep_in);
+    print_deps(&links.incoming);
 
     println!("  Outgoing:");
-    print_deps(dep_out);
+    print_deps(&links.outgoing);
 }
 
-pub fn print_file_info(graph: &Graph, file_name: &str) {
-    let f_ref = get_file_ref_or_fail(&graph, &file_name);
+pub fn print_file_info(graph: &Graph, file_name: &str) -> Result<()> {
+    let f_ref = graph.file_name_to_ref(file_name)?;
 
     println!("Incoming:");
     for &fi in &graph.file_links[f_ref].incoming_links {
-        println!("  {}", graph.files[fi].path);
+        println!("  {}", graph.edge_location(&graph.edge(fi, f_ref)));
     }
 
     println!("Outgoing:");
-    for &fo in &graph.file_links[f_ref].outgoing_links {
-        println!("  {}", graph.files[fo].path);
+    let links = &graph.file_links[f_ref];
+    for (&fo, origin) in links.outgoing_links.iter().zip(&links.outgoing_origins) {
+        let units = &origin.translation_units;
+        let line = match origin.include.map(|i| &graph.files[f_ref].includes[i]) {
+            Some(include) => match include.directive.label() {
+                Some(label) => format!(" (line {}, {})", include.line, label),
+                None => format!(" (line {})", include.line),
+            },
+            None => String::new(),
+        };
+        if units.is_empty() {
+            println!("  {}{}", graph.files[fo].path, line);
+        } else {
+            println!("  {}{} only in:", graph.files[fo].path, line);
+            for &unit in units {
+                println!("    {}", graph.files[unit].path);
+            }
+        }
     }
-}
-
-pub fn print_headers(graph: &Graph, component_name: String, verbose: bool) {
-    let c_ref = get_component_ref_or_fail(&graph, &component_name);
 
-    let mut public_headers = vec![];
-    let mut private_headers = vec![];
-    let mut solo_headers = vec![];
-    let mut dead_headers = vec![];
-    for &file_ref in &graph.component_files[c_ref] {
-        let links = &graph.file_links[file_ref].incoming_links;
-        let c = graph.file_components[file_ref];
-        let public_links: Vec<FileRef> = links
-            .iter()
-            .filter(|&f_ref| graph.file_components[*f_ref] != c || graph.file_is_public[*f_ref])
-            .cloned()
-            .collect();
-        if !public_links.is_empty() {
-            public_headers.push((file_ref, public_links));
-            continue;
+    let file = &graph.files[f_ref];
+    if let Some(module) = &file.module {
+        let kind = if module.exported {
+            "interface"
+        } else {
+            "implementation"
+        };
+        println!("Module: {} ({})", module.name, kind);
+    }
+    if !file.imports.is_empty() {
+        println!("Imports:");
+        for import in &file.imports {
+            println!("  {} (line {})", import.name, import.line);
         }
+    }
+    Ok(())
+}
 
-        if graph.is_header(file_ref) {
-            if !links.is_empty() {
-                if links.len() == 1 {
-                    let fi = links[0];
-                    let base_name = graph.files[file_ref].path.rsplit('/').next().unwrap();
-                    if let Some(base_name) = base_name.rsplit('.').nth(1) {
-                        if graph.files[fi].path.contains(base_name) {
-                            // solo header: file included only once, by a similarly-named source file
-                            solo_headers.push((file_ref, vec![fi]));
-                            continue;
-                        }
-                    }
-                }
-                private_headers.push((file_ref, vec![]));
-            } else {
-                dead_headers.push((file_ref, vec![]));
+pub fn print_modules(graph: &Graph, module_name: Option<String>) -> Result<()> {
+    let module_name = match module_name {
+        Some(name) => name,
+        None => {
+            for module in &graph.modules {
+                println!("{} ({})", module.name, module.units.len());
             }
+            return Ok(());
         }
+    };
+    let m_ref = graph.module_name_to_ref(&module_name)?;
+
+    println!("Units:");
+    for &f in &graph.modules[m_ref].units {
+        println!("  {}", graph.files[f].path);
+    }
+    let links = &graph.module_links[m_ref];
+    println!("Imported by:");
+    for &mi in &links.incoming_links {
+        println!("  {}", graph.modules[mi].name);
     }
+    println!("Imports:");
+    for &mo in &links.outgoing_links {
+        println!("  {}", graph.modules[mo].name);
+    }
+    Ok(())
+}
 
-    let mut sections = [
-        ("Public", public_headers),
-        ("Private", private_headers),
-        ("Solo", solo_headers),
-        ("Dead", dead_headers),
-    ];
-    for (title, headers) in sections.iter_mut() {
-        if headers.is_empty() {
-            continue;
-        }
-        println!("{} headers:", title);
-        headers.sort_by(|&(f1, _), &(f2, _)| graph.files[f1].path.cmp(&graph.files[f2].path));
-        for (f, incoming_links) in headers {
-            println!("  {}", graph.files[*f].path);
-            if verbose {
-                for &fi in &*incoming_links {
-                    println!("    <- {}", graph.files[fi].path);
-                }
+pub fn print_headers(graph: &Graph, component_name: String, verbose: bool) -> Result<()> {
+    let c_ref = graph.component_name_to_ref(&component_name)?;
+    let classes = graph.classify_headers(c_ref);
+
+    let public = classes.public.iter().map(|(f, fi)| (*f, fi.as_slice()));
+    print_header_section(graph, "Public", public.collect(), verbose);
+    let private = classes.private.iter().map(|&f| (f, &[][..]));
+    print_header_section(graph, "Private", private.collect(), verbose);
+    let solo = classes
+        .solo
+        .iter()
+        .map(|(f, fi)| (*f, std::slice::from_ref(fi)));
+    print_header_section(graph, "Solo", solo.collect(), verbose);
+    let dead = classes.dead.iter().map(|&f| (f, &[][..]));
+    print_header_section(graph, "Dead", dead.collect(), verbose);
+    Ok(())
+}
+
+fn print_header_section(
+    graph: &Graph,
+    title: &str,
+    headers: Vec<(FileRef, &[FileRef])>,
+    verbose: bool,
+) {
+    if headers.is_empty() {
+        return;
+    }
+    println!("{} headers:", title);
+    for (f, incoming_links) in headers {
+        println!("  {}", graph.files[f].path);
+        if verbose {
+            for &fi in incoming_links {
+                println!("    <- {}", graph.files[fi].path);
             }
         }
     }
@@ -143,175 +183,105 @@ pub fn print_shortest(
     component_to: &str,
     verbose: bool,
     only_public: bool,
-) {
-    let c_from = get_component_ref_or_fail(&graph, component_from);
-    let c_to = get_component_ref_or_fail(&graph, component_to);
-
-    let mut dists = vec![(0usize, u32::max_value()); graph.components.len()];
-    dists[c_from] = (c_from, 0);
-
-    let mut queue = std::collections::VecDeque::new();
-    queue.push_back(c_from);
-
-    while let Some(c_source) = queue.pop_front() {
-        let dist = dists[c_source].1 + 1;
+) -> Result<()> {
+    let c_from = graph.component_name_to_ref(component_from)?;
+    let c_to = graph.component_name_to_ref(component_to)?;
 
-        for &f in graph.component_files[c_source].iter() {
-            if c_source == c_from && only_public && !graph.file_is_public[f] {
-                continue;
-            }
-            for fo in graph.file_links[f].outgoing_links.iter() {
-                let c = graph.file_components[*fo];
-                if dists[c].1 > dist {
-                    dists[c] = (c_source, dist);
-                    queue.push_back(c);
-                }
-            }
+    let result = match graph.shortest_path(c_from, c_to, only_public) {
+        Some(path) => path,
+        None => {
+            println!("No path found.");
+            return Ok(());
         }
-    }
-
-    if dists[c_to].1 == u32::max_value() {
-        println!("No path found.");
-        return;
-    }
-
-    let mut result = vec![];
-    let mut c = c_to;
-    while c != c_from {
-        result.push(c);
-        c = dists[c].0;
-    }
-    result.push(c_from);
-    result.reverse();
+    };
 
     for i in 0..result.len() {
         let c = result[i];
         println!("{}", graph.components[c].nice_name());
         if verbose && i + 1 != result.len() {
-            let c2 = result[i + 1];
-            for &f in graph.component_files[c].iter() {
-                if c == c_from && only_public && !graph.file_is_public[f] {
-                    continue;
-                }
-                for &fo in graph.file_links[f].outgoing_links.iter() {
-                    if graph.file_components[fo] == c2 {
-                        println!("  {} -> {}", graph.files[f].path, graph.files[fo].path);
-                    }
-                }
+            let from_public = c == c_from && only_public;
+            for (f, fo) in graph.links_between(c, result[i + 1], from_public) {
+                println!("  {} -> {}", graph.files[f].path, graph.files[fo].path);
             }
         }
     }
+    Ok(())
 }
 
-pub fn show_sccs(project: &Graph) {
-    let sccs = Tarjan::run(project);
-
-    for mut scc in sccs.into_iter().filter(|c| c.len() > 1) {
-        scc.reverse();
+/// Prints the groups of components that link in cycles and returns how many there are.
+pub fn show_sccs(project: &Graph) -> usize {
+    let sccs = project.strongly_connected_components();
+    for scc in &sccs {
         println!("Strongly Connected:");
-        for c_ref in scc {
+        for &c_ref in scc {
             println!("  {}", project.components[c_ref].nice_name());
         }
     }
+    sccs.len()
 }
 
-struct Tarjan {
-    index: i32,
-    indices: Vec<i32>,
-    lowlink: Vec<i32>,
-    on_stack: Vec<bool>,
-    stack: Vec<ComponentRef>,
-    sccs: Vec<Vec<ComponentRef>>,
+/// How the `diagnostics` command prints.
+pub enum Format {
+    Text,
+    Json,
 }
 
-impl Tarjan {
-    fn run(project: &Graph) -> Vec<Vec<ComponentRef>> {
-        let mut t = Tarjan {
-            index: 0,
-            indices: std::iter::repeat(-1)
-                .take(project.components.len())
-                .collect(),
-            lowlink: std::iter::repeat(-1)
-                .take(project.components.len())
-                .collect(),
-            on_stack: std::iter::repeat(false)
-                .take(project.components.len())
-                .collect(),
-            stack: vec![],
-            sccs: vec![],
-        };
-        for v in 0..project.components.len() {
-            if t.indices[v] == -1 {
-                t.strong_connect(v, project);
-            }
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown format {}, expected text or json", s)),
         }
-        t.sccs
     }
+}
 
-    fn strong_connect(&mut self, v: ComponentRef, project: &Graph) {
-        // Set the depth index for v to the smallest unused index
-        self.indices[v] = self.index;
-        self.lowlink[v] = self.index;
-        self.index += 1;
-        self.stack.push(v);
-        self.on_stack[v] = true;
+#[derive(Serialize)]
+struct DiagnosticsReport<'a> {
+    counts: BTreeMap<&'a str, usize>,
+    diagnostics: Vec<&'a Diagnostic>,
+}
 
-        // Consider successors of v
-        for w in project.component_files[v]
-            .iter()
-            .flat_map(|&f| &project.file_links[f].outgoing_links)
-            .map(|f| project.file_components[*f])
-            .filter(|&c| c != v)
-        {
-            if self.indices[w] == -1 {
-                // Successor w has not yet been visited; recurse on it
-                self.strong_connect(w, &project);
-                self.lowlink[v] = std::cmp::min(self.lowlink[v], self.lowlink[w]);
-            } else if self.on_stack[w] {
-                // Successor w is in stack S and hence in the current SCC
-                // If w is not on stack, then (v, w) is a cross-edge in the DFS tree and must be ignored
-                // Note: The next line may look odd - but is correct.
-                // It says w.index not w.lowlink; that is deliberate and from the original paper
-                self.lowlink[v] = std::cmp::min(self.lowlink[v], self.indices[w]);
-            }
-        }
-        // If v is a root node, pop the stack and generate an SCC
-        if self.lowlink[v] == self.indices[v] {
-            let mut scc = vec![];
-            loop {
-                let w = self.stack.pop().expect("empty stack?");
-                self.on_stack[w] = false;
-                scc.push(w);
-                if w == v {
-                    break;
+pub fn print_diagnostics(graph: &Graph, categories: &[Category], format: &Format) -> Result<()> {
+    let groups = graph.diagnostics_by_category(categories);
+    match format {
+        Format::Text => {
+            for (category, diagnostics) in &groups {
+                println!("{} ({})", category.name(), diagnostics.len());
+                for d in diagnostics {
+                    println!("  {}", diagnostic_line(d));
                 }
             }
-            self.sccs.push(scc);
+            let total: usize = groups.iter().map(|(_, d)| d.len()).sum();
+            println!("{} diagnostics", total);
+        }
+        Format::Json => {
+            let report = DiagnosticsReport {
+                counts: groups.iter().map(|(c, d)| (c.name(), d.len())).collect(),
+                diagnostics: groups.into_iter().flat_map(|(_, d)| d).collect(),
+            };
+            let json = serde_json::to_string_pretty(&report).map_err(std::io::Error::from);
+            println!("{}", json.context("write JSON to", "stdout")?);
         }
     }
+    Ok(())
 }
 
-fn get_component_ref_or_fail(graph: &Graph, component_name: &str) -> ComponentRef {
-    match graph.component_name_to_ref(component_name) {
-        Some(c) => c,
-        None => {
-            eprintln!("component not found: {}", component_name);
-            std::process::exit(1);
+/// Prints the diagnostics of the categories shown by default and of `categories` to stderr,
+/// apart from the output of the command.
+pub fn print_load_diagnostics(graph: &Graph, categories: &[Category]) {
+    for d in &graph.diagnostics {
+        if d.category.shown_by_default() || categories.contains(&d.category) {
+            eprintln!("{}", diagnostic_line(d));
         }
     }
 }
 
-fn get_file_ref_or_fail(graph: &Graph, file_name: &str) -> FileRef {
-    match graph
-        .files
-        .iter()
-        .enumerate()
-        .find(|(_, f)| f.path == file_name)
-    {
-        Some(f) => f.0,
-        None => {
-            eprintln!("file not found: {}", file_name);
-            std::process::exit(1);
-        }
+fn diagnostic_line(d: &Diagnostic) -> String {
+    match d.location() {
+        location if location.is_empty() => format!("{}: {}", d.severity.name(), d.message),
+        location => format!("{}: {}: {}", location, d.severity.name(), d.message),
     }
 }
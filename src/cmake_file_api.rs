@@ -0,0 +1,485 @@
+//! Reading of the codemodel that the CMake File API writes to
+//! `<build>/.cmake/api/v1/reply`: targets with their sources, and the include paths and defines
+//! each source is compiled with. The dependencies between targets are not read; the links of the
+//! graph come from the includes that the compile settings resolve.
+
+use crate::cmake_targets::Target;
+use crate::compile_commands::{self, CompileCommands, CompileSettings};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The reply index. Its entries are either shared replies like `"codemodel-v2"` or the replies
+/// to a client's queries under `"client-<name>"`, which differ in shape.
+#[derive(Deserialize)]
+struct Index {
+    reply: HashMap<String, serde_json::Value>,
+}
+
+impl Index {
+    /// The reply file of an object kind like `codemodel-v2`, shared or of any client.
+    fn reply_file(&self, kind: &str) -> Option<&str> {
+        if let Some(file) = self.reply.get(kind).and_then(json_file) {
+            return Some(file);
+        }
+        let (name, major) = kind.rsplit_once("-v")?;
+        let mut clients: Vec<(&String, &serde_json::Value)> = self
+            .reply
+            .iter()
+            .filter(|(key, _)| key.starts_with("client-"))
+            .collect();
+        clients.sort_by_key(|(key, _)| *key);
+        clients.into_iter().find_map(|(_, client)| {
+            // a query file named after the kind, or a response to a query.json request
+            let stateless = client.get(kind).and_then(json_file);
+            stateless.or_else(|| {
+                let responses = client.get("query.json")?.get("responses")?.as_array()?;
+                let is_kind = |response: &serde_json::Value| {
+                    let version = response.get("version")?.get("major")?.as_u64()?;
+                    Some(response.get("kind")?.as_str()? == name && version.to_string() == major)
+                };
+                responses
+                    .iter()
+                    .find(|response| is_kind(response) == Some(true))
+                    .and_then(json_file)
+            })
+        })
+    }
+}
+
+#[derive(Deserialize)]
+struct Codemodel {
+    paths: Paths,
+    configurations: Vec<Configuration>,
+}
+
+#[derive(Deserialize)]
+struct Paths {
+    source: String,
+    build: String,
+}
+
+#[derive(Deserialize)]
+struct Configuration {
+    targets: Vec<TargetReference>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TargetReference {
+    json_file: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct TargetFile {
+    name: String,
+    paths: Paths,
+    #[serde(default)]
+    sources: Vec<Source>,
+    #[serde(default)]
+    compile_groups: Vec<CompileGroup>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Source {
+    path: String,
+    compile_group_index: Option<usize>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CompileGroup {
+    language: Option<String>,
+    #[serde(default)]
+    compile_command_fragments: Vec<Fragment>,
+    #[serde(default)]
+    includes: Vec<IncludeDirectory>,
+    #[serde(default)]
+    defines: Vec<Define>,
+}
+
+#[derive(Deserialize)]
+struct Fragment {
+    fragment: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct IncludeDirectory {
+    path: String,
+    #[serde(default)]
+    is_system: bool,
+}
+
+#[derive(Deserialize)]
+struct Define {
+    define: String,
+}
+
+#[derive(Deserialize)]
+struct Toolchains {
+    toolchains: Vec<Toolchain>,
+}
+
+#[derive(Deserialize)]
+struct Toolchain {
+    language: String,
+    compiler: Compiler,
+}
+
+#[derive(Deserialize)]
+struct Compiler {
+    path: Option<String>,
+}
+
+/// Loads the targets of the first configuration, with their sources relative to `root`, and the
+/// compile settings of each (canonicalized) source file.
+pub fn load(build_dir: &str, root: &str) -> io::Result<(Vec<Target>, CompileCommands)> {
+    let reply_dir = Path::new(build_dir).join(".cmake/api/v1/reply");
+    let index: Index = read_json(&newest_index(&reply_dir)?)?;
+    let reply_file = |kind: &str| index.reply_file(kind).map(|f| reply_dir.join(f));
+    let codemodel_file = reply_file("codemodel-v2").ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "no codemodel-v2 in the reply index",
+        )
+    })?;
+    let codemodel: Codemodel = read_json(&codemodel_file)?;
+
+    let mut compilers: HashMap<String, String> = HashMap::new();
+    if let Some(toolchains_file) = reply_file("toolchains-v1") {
+        let toolchains: Toolchains = read_json(&toolchains_file)?;
+        for toolchain in toolchains.toolchains {
+            if let Some(path) = toolchain.compiler.path {
+                compilers.insert(toolchain.language, path);
+            }
+        }
+    }
+
+    let root = PathBuf::from(root).canonicalize()?;
+    let source_dir = Path::new(&codemodel.paths.source);
+    let relative_to_root = |path: &Path| -> Option<String> {
+        let path = path.canonicalize().ok()?;
+        let relative = path.strip_prefix(&root).ok()?;
+        Some(relative.to_str()?.replace('\\', "/"))
+    };
+
+    let mut targets = vec![];
+    let mut settings = HashMap::new();
+    let configuration = match codemodel.configurations.first() {
+        Some(configuration) => configuration,
+        None => return Ok((targets, settings)),
+    };
+    for reference in &configuration.targets {
+        let target: TargetFile = read_json(&reply_dir.join(&reference.json_file))?;
+        let target_build_dir = Path::new(&codemodel.paths.build).join(&target.paths.build);
+        let group_settings: Vec<CompileSettings> = target
+            .compile_groups
+            .iter()
+            .map(|group| {
+                let compiler = group
+                    .language
+                    .as_ref()
+                    .and_then(|l| compilers.get(l))
+                    .map(String::as_str)
+                    .unwrap_or(if cfg!(windows) { "cl" } else { "c++" });
+                let arguments = group_arguments(group, compiler);
+                compile_commands::parse_arguments(&arguments, &target_build_dir)
+            })
+            .collect();
+
+        let mut sources = vec![];
+        for source in &target.sources {
+            let path = source_dir.join(&source.path);
+            if let Some(relative) = relative_to_root(&path) {
+                sources.push(relative);
+            }
+            if let (Some(index), Ok(path)) = (source.compile_group_index, path.canonicalize()) {
+                if let Some(group) = group_settings.get(index) {
+                    settings
+                        .entry(path.to_string_lossy().into_owned())
+                        .or_default()
+                        .push(group.clone());
+                }
+            }
+        }
+        targets.push(Target {
+            name: target.name,
+            directory: relative_to_root(&source_dir.join(&target.paths.source)).unwrap_or_default(),
+            sources,
+        });
+    }
+    Ok((targets, settings))
+}
+
+/// Spells out a compile group as a command line, so that it is parsed like compile_commands.json.
+fn group_arguments(group: &CompileGroup, compiler: &str) -> Vec<String> {
+    let msvc = compile_commands::is_msvc(compiler);
+    let mut arguments = vec![compiler.to_string()];
+    // the source files are not part of the command line, so their language is spelled out
+    if group.language.as_deref() == Some("CXX") {
+        arguments.push(if msvc { "/TP" } else { "-xc++" }.to_string());
+    }
+    for fragment in &group.compile_command_fragments {
+        let windows_rules = compile_commands::uses_windows_rules(compiler);
+        arguments.extend(compile_commands::split_command(
+            &fragment.fragment,
+            windows_rules,
+        ));
+    }
+    // as CMake orders them: the system include directories after the others
+    let includes = group
+        .includes
+        .iter()
+        .filter(|i| !i.is_system)
+        .chain(group.includes.iter().filter(|i| i.is_system));
+    for include in includes {
+        let flag = match (msvc, include.is_system) {
+            (true, _) => "/I",
+            (false, false) => "-I",
+            (false, true) => "-isystem",
+        };
+        arguments.push(flag.to_string());
+        arguments.push(include.path.clone());
+    }
+    for define in &group.defines {
+        arguments.push(if msvc { "/D" } else { "-D" }.to_string());
+        arguments.push(define.define.clone());
+    }
+    arguments
+}
+
+/// CMake names index files by time, so the newest one sorts last.
+fn newest_index(reply_dir: &Path) -> io::Result<PathBuf> {
+    let mut indices: Vec<PathBuf> = std::fs::read_dir(reply_dir)?
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            name.starts_with("index-") && name.ends_with(".json")
+        })
+        .collect();
+    indices.sort();
+    indices.pop().ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!(
+                "no index file in {:?}, did CMake run with a query?",
+                reply_dir
+            ),
+        )
+    })
+}
+
+fn json_file(reply: &serde_json::Value) -> Option<&str> {
+    reply.get("jsonFile")?.as_str()
+}
+
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> io::Result<T> {
+    let f = std::fs::File::open(path)?;
+    Ok(serde_json::from_reader(io::BufReader::new(f))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessor;
+    use crate::test_tree::TestTree;
+    use serde_json::json;
+
+    #[test]
+    fn find_reply_files() {
+        let codemodel = |file: &str| json!({"kind": "codemodel", "jsonFile": file});
+        let response = |kind: &str, major: u64, file: &str| json!({"kind": kind, "version": {"major": major, "minor": 0}, "jsonFile": file});
+        let cases: &[(serde_json::Value, Option<&str>)] = &[
+            // index replies, the codemodel-v2 file
+            (
+                json!({"codemodel-v2": codemodel("shared.json")}),
+                Some("shared.json"),
+            ),
+            (
+                json!({
+                    "codemodel-v2": codemodel("shared.json"),
+                    "client-a": {"codemodel-v2": codemodel("a.json")},
+                }),
+                Some("shared.json"),
+            ),
+            // a stateless query of a client
+            (
+                json!({"client-a": {"codemodel-v2": codemodel("a.json")}}),
+                Some("a.json"),
+            ),
+            // of several clients, the first by name
+            (
+                json!({
+                    "client-b": {"codemodel-v2": codemodel("b.json")},
+                    "client-a": {"codemodel-v2": codemodel("a.json")},
+                }),
+                Some("a.json"),
+            ),
+            // a stateful query.json of a client
+            (
+                json!({"client-a": {"query.json": {"responses": [
+                    response("cache", 2, "cache.json"),
+                    response("codemodel", 1, "old.json"),
+                    response("codemodel", 2, "a.json"),
+                ]}}}),
+                Some("a.json"),
+            ),
+            (
+                json!({"client-a": {"query.json": {"responses": [
+                    response("codemodel", 1, "old.json"),
+                    {"error": "unknown request kind 'codemodel'"},
+                ]}}}),
+                None,
+            ),
+            // an error instead of a file
+            (
+                json!({"client-a": {"codemodel-v2": {"error": "unknown query file"}}}),
+                None,
+            ),
+            (json!({"cache-v2": {"jsonFile": "cache.json"}}), None),
+            (json!({}), None),
+        ];
+        for (reply, expected) in cases {
+            let index: Index = serde_json::from_value(json!({ "reply": reply })).unwrap();
+            assert_eq!(index.reply_file("codemodel-v2"), *expected, "{}", reply);
+        }
+    }
+
+    #[test]
+    fn spell_out_compile_groups() {
+        let group: CompileGroup = serde_json::from_value(json!({
+            "language": "CXX",
+            "compileCommandFragments": [{"fragment": "-O2 -DNDEBUG"}],
+            "includes": [
+                {"path": "/sys", "isSystem": true},
+                {"path": "/a b"},
+            ],
+            "defines": [{"define": "USE_X=1"}],
+        }))
+        .unwrap();
+        let cases: &[(&str, &[&str])] = &[
+            (
+                "/usr/bin/g++",
+                &[
+                    "/usr/bin/g++",
+                    "-xc++",
+                    "-O2",
+                    "-DNDEBUG",
+                    "-I",
+                    "/a b",
+                    "-isystem",
+                    "/sys",
+                    "-D",
+                    "USE_X=1",
+                ],
+            ),
+            (
+                "cl.exe",
+                &[
+                    "cl.exe", "/TP", "-O2", "-DNDEBUG", "/I", "/a b", "/I", "/sys", "/D", "USE_X=1",
+                ],
+            ),
+        ];
+        for (compiler, expected) in cases {
+            assert_eq!(group_arguments(&group, compiler), *expected);
+        }
+        let group: CompileGroup = serde_json::from_value(json!({"language": "C"})).unwrap();
+        assert_eq!(group_arguments(&group, "cc"), ["cc"]);
+    }
+
+    #[test]
+    fn load_reply() {
+        let tree = TestTree::new("cmake-reply", &[]);
+        let source = tree.path("src");
+        let build = tree.path("build");
+        let codemodel = json!({
+            "paths": {"source": source, "build": build},
+            "configurations": [
+                {"targets": [{"jsonFile": "target-core.json"}, {"jsonFile": "target-app.json"}]},
+                {"targets": []},
+            ],
+        });
+        let core = json!({
+            "name": "core",
+            "paths": {"source": "core", "build": "core"},
+            "sources": [
+                {"path": "core/core.cpp", "compileGroupIndex": 0},
+                {"path": "core/core.h"},
+                {"path": "core/generated.cpp", "compileGroupIndex": 0},
+            ],
+            "compileGroups": [{
+                "language": "CXX",
+                "includes": [{"path": source.join("core")}],
+                "defines": [{"define": "USE_X=1"}],
+            }],
+        });
+        let app = json!({
+            "name": "app",
+            "paths": {"source": ".", "build": "."},
+            "sources": [{"path": "core/core.cpp", "compileGroupIndex": 0}],
+            "compileGroups": [{"language": "CXX"}],
+        });
+        let toolchains = json!({"toolchains": [{"language": "CXX", "compiler": {"path": "g++"}}]});
+        let files = [
+            ("src/core/core.cpp", json!(null)),
+            ("src/core/core.h", json!(null)),
+            // a stale index, and the newest one, whose client asked for the codemodel
+            (
+                "build/.cmake/api/v1/reply/index-2020-01-01T00-00-00-0000.json",
+                json!({"reply": {}}),
+            ),
+            (
+                "build/.cmake/api/v1/reply/index-2024-01-01T00-00-00-0000.json",
+                json!({"reply": {"client-cpdep": {
+                    "codemodel-v2": {"jsonFile": "codemodel.json"},
+                    "toolchains-v1": {"jsonFile": "toolchains.json"},
+                }}}),
+            ),
+            ("build/.cmake/api/v1/reply/codemodel.json", codemodel),
+            ("build/.cmake/api/v1/reply/target-core.json", core),
+            ("build/.cmake/api/v1/reply/target-app.json", app),
+            ("build/.cmake/api/v1/reply/toolchains.json", toolchains),
+        ];
+        for (path, json) in &files {
+            tree.write(path, &serde_json::to_vec(json).unwrap());
+        }
+
+        let (targets, settings) = load(build.to_str().unwrap(), source.to_str().unwrap()).unwrap();
+        let targets: Vec<(&str, &str, &[String])> = targets
+            .iter()
+            .map(|t| (t.name.as_str(), t.directory.as_str(), t.sources.as_slice()))
+            .collect();
+        assert_eq!(
+            targets,
+            [
+                (
+                    "core",
+                    "core",
+                    &["core/core.cpp".to_string(), "core/core.h".to_string()][..]
+                ),
+                ("app", "", &["core/core.cpp".to_string()][..]),
+            ]
+        );
+        // the file is compiled for both targets
+        let core_cpp = source.join("core/core.cpp").canonicalize().unwrap();
+        let core_settings = &settings[core_cpp.to_str().unwrap()];
+        assert_eq!(settings.len(), 1);
+        assert_eq!(core_settings.len(), 2);
+        let include = source.join("core").canonicalize().unwrap();
+        assert_eq!(core_settings[0].include_paths, [include.to_str().unwrap()]);
+        assert!(core_settings[1].include_paths.is_empty());
+        let evaluate = |settings: &CompileSettings, expression| {
+            preprocessor::evaluate(expression, &settings.macros)
+        };
+        assert_eq!(evaluate(&core_settings[0], "USE_X == 1"), Some(true));
+        assert_eq!(evaluate(&core_settings[1], "defined USE_X"), Some(false));
+        assert_eq!(
+            evaluate(&core_settings[0], "__cplusplus >= 201103L"),
+            Some(true)
+        );
+    }
+}
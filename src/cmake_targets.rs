@@ -0,0 +1,423 @@
+//! Reading of CMake targets and their sources from `CMakeLists.txt` files.
+//!
+//! Only literal arguments and variables set by `project()` and `set()` are understood, with a
+//! directory seeing the variables of its parent directories. Sources that use other variables
+//! (than the current directory) or generator expressions are skipped, and so are targets whose
+//! names do.
+
+use crate::diagnostics::{Category, Diagnostic, Diagnostics};
+use crate::file_collector::Component;
+use std::collections::HashMap;
+use std::path::Path;
+
+pub struct Target {
+    pub name: String,
+    /// the directory of the `CMakeLists.txt` that defines the target, relative to the root
+    pub directory: String,
+    /// the sources listed for the target, relative to the root with unix slashes
+    pub sources: Vec<String>,
+}
+
+const LIBRARY_KEYWORDS: [&str; 8] = [
+    "STATIC",
+    "SHARED",
+    "MODULE",
+    "OBJECT",
+    "INTERFACE",
+    "UNKNOWN",
+    "GLOBAL",
+    "EXCLUDE_FROM_ALL",
+];
+const EXECUTABLE_KEYWORDS: [&str; 3] = ["WIN32", "MACOSX_BUNDLE", "EXCLUDE_FROM_ALL"];
+
+type Variables = HashMap<String, String>;
+
+/// An argument of a command, without its quotes.
+struct Argument {
+    text: String,
+    quoted: bool,
+}
+
+/// Reads the targets from the `CMakeLists.txt` files of the component directories, in the order
+/// of the components.
+pub fn load(root: &str, components: &[Component], diagnostics: &Diagnostics) -> Vec<Target> {
+    // parents first, so that their variables are known to their subdirectories
+    let mut order: Vec<usize> = (0..components.len()).collect();
+    order.sort_by_key(|&i| {
+        components[i]
+            .path
+            .split('/')
+            .filter(|s| !s.is_empty())
+            .count()
+    });
+
+    let mut directory_variables: HashMap<&str, Variables> = HashMap::new();
+    let mut component_targets: Vec<Vec<Target>> = components.iter().map(|_| vec![]).collect();
+    let mut extra_sources: Vec<(String, String, Vec<String>)> = vec![];
+    for i in order {
+        let directory = &components[i].path;
+        let path = Path::new(root).join(directory).join("CMakeLists.txt");
+        let text = match std::fs::read(&path) {
+            Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+            Err(_) => continue,
+        };
+        let file = Path::new(directory).join("CMakeLists.txt");
+        let file = file.to_string_lossy().replace('\\', "/");
+        let mut variables = parent_variables(directory, &directory_variables);
+        for (command, arguments) in commands(&text) {
+            let arguments = expand_arguments(&arguments, &variables);
+            let (name, rest) = match arguments.split_first() {
+                Some(split) => split,
+                None => continue,
+            };
+            match command.as_str() {
+                "project" => {
+                    variables.insert("PROJECT_NAME".to_string(), name.clone());
+                    variables
+                        .entry("CMAKE_PROJECT_NAME".to_string())
+                        .or_insert_with(|| name.clone());
+                }
+                "set" => set_variable(&mut variables, name, rest),
+                "add_library" | "add_executable" if name.contains("${") || name.contains("$<") => {
+                    diagnostics.report(
+                        Diagnostic::new(
+                            Category::UnresolvedTarget,
+                            format!(
+                                "target name {} is not resolved, its files stay in directory \
+                                 components",
+                                name
+                            ),
+                        )
+                        .file(&file),
+                    );
+                }
+                "add_library" | "add_executable" => {
+                    // imported and alias targets have no sources of their own
+                    if rest.iter().any(|a| a == "IMPORTED" || a == "ALIAS") {
+                        continue;
+                    }
+                    let keywords: &[&str] = if command == "add_library" {
+                        &LIBRARY_KEYWORDS
+                    } else {
+                        &EXECUTABLE_KEYWORDS
+                    };
+                    let sources = rest
+                        .iter()
+                        .filter(|a| !keywords.contains(&a.as_str()))
+                        .filter_map(|a| source_path(directory, a))
+                        .collect();
+                    component_targets[i].push(Target {
+                        name: name.clone(),
+                        directory: directory.clone(),
+                        sources,
+                    });
+                }
+                "target_sources" => {
+                    extra_sources.push((name.clone(), directory.clone(), target_sources(rest)));
+                }
+                _ => {}
+            }
+        }
+        directory_variables.insert(directory, variables);
+    }
+    let mut targets: Vec<Target> = component_targets.into_iter().flatten().collect();
+
+    // target_sources may come before or after the target, or from another directory
+    let name_to_target: HashMap<String, usize> = targets
+        .iter()
+        .enumerate()
+        .map(|(i, t)| (t.name.clone(), i))
+        .collect();
+    for (name, directory, sources) in extra_sources {
+        if let Some(&i) = name_to_target.get(&name) {
+            let sources = sources.iter().filter_map(|s| source_path(&directory, s));
+            targets[i].sources.extend(sources);
+        }
+    }
+    targets
+}
+
+/// The variables of the nearest parent directory that has a `CMakeLists.txt`.
+fn parent_variables(directory: &str, directory_variables: &HashMap<&str, Variables>) -> Variables {
+    let mut parent = directory;
+    while !parent.is_empty() {
+        parent = parent.rsplit_once('/').map_or("", |(parent, _)| parent);
+        if let Some(variables) = directory_variables.get(parent) {
+            return variables.clone();
+        }
+    }
+    Variables::new()
+}
+
+/// Applies a `set` call. Lists are joined with `;` as in CMake, and cache entries set their
+/// value like normal variables.
+fn set_variable(variables: &mut Variables, name: &str, arguments: &[String]) {
+    if arguments.iter().any(|a| a == "PARENT_SCOPE") {
+        return;
+    }
+    let values: Vec<&str> = arguments
+        .iter()
+        .map(String::as_str)
+        .take_while(|&a| a != "CACHE")
+        .collect();
+    if values.is_empty() {
+        variables.remove(name);
+    } else {
+        variables.insert(name.to_string(), values.join(";"));
+    }
+}
+
+/// Expands the variables in the arguments of a command. As in CMake, an unquoted argument that
+/// holds a list after expansion gives an argument for each non-empty element, while a quoted one
+/// stays a single argument.
+fn expand_arguments(arguments: &[Argument], variables: &Variables) -> Vec<String> {
+    let mut expanded = vec![];
+    for argument in arguments {
+        let text = expand_variables(&argument.text, variables);
+        if argument.quoted {
+            expanded.push(text);
+        } else {
+            let elements = text.split(';').filter(|e| !e.is_empty());
+            expanded.extend(elements.map(str::to_string));
+        }
+    }
+    expanded
+}
+
+/// Replaces the references to known variables, like `${PROJECT_NAME}`. Others are kept as they
+/// are, so that the callers can tell that the argument isn't resolved.
+fn expand_variables(argument: &str, variables: &Variables) -> String {
+    let mut expanded = String::new();
+    let mut rest = argument;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        expanded.push_str(&rest[..start]);
+        match variables.get(&rest[start + 2..end]) {
+            Some(value) => expanded.push_str(value),
+            None => expanded.push_str(&rest[start..=end]),
+        }
+        rest = &rest[end + 1..];
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+/// Returns the file arguments of a `target_sources` call, skipping the scope keywords and the
+/// options of file sets.
+fn target_sources(arguments: &[String]) -> Vec<String> {
+    let mut sources = vec![];
+    let mut skip_values = false;
+    let mut skip_next = false;
+    for argument in arguments {
+        match argument.as_str() {
+            "PRIVATE" | "PUBLIC" | "INTERFACE" | "FILES" => skip_values = false,
+            "FILE_SET" | "TYPE" => skip_next = true,
+            "BASE_DIRS" => skip_values = true,
+            _ if skip_next => skip_next = false,
+            _ if skip_values => {}
+            _ => sources.push(argument.clone()),
+        }
+    }
+    sources
+}
+
+/// Resolves a source argument against the directory of its `CMakeLists.txt`.
+fn source_path(directory: &str, argument: &str) -> Option<String> {
+    let argument = argument
+        .trim_start_matches("${CMAKE_CURRENT_SOURCE_DIR}/")
+        .trim_start_matches("${CMAKE_CURRENT_LIST_DIR}/");
+    if argument.is_empty()
+        || argument.contains("${")
+        || argument.contains("$<")
+        || Path::new(argument).is_absolute()
+    {
+        return None;
+    }
+    let mut segments: Vec<&str> = directory.split('/').filter(|s| !s.is_empty()).collect();
+    for segment in argument.split(&['/', '\\'][..]) {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            s => segments.push(s),
+        }
+    }
+    Some(segments.join("/"))
+}
+
+/// Splits a CMake file into command invocations, with the command name in lower case and the
+/// arguments unquoted.
+fn commands(text: &str) -> Vec<(String, Vec<Argument>)> {
+    let mut commands = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c == '#' {
+            skip_comment(&mut chars);
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut name = String::new();
+            while let Some(&c) = chars.peek() {
+                if !(c.is_ascii_alphanumeric() || c == '_') {
+                    break;
+                }
+                name.push(c.to_ascii_lowercase());
+                chars.next();
+            }
+            while chars
+                .peek()
+                .map(|c| *c == ' ' || *c == '\t')
+                .unwrap_or(false)
+            {
+                chars.next();
+            }
+            if chars.peek() == Some(&'(') {
+                chars.next();
+                commands.push((name, arguments(&mut chars)));
+            }
+        } else {
+            chars.next();
+        }
+    }
+    commands
+}
+
+/// Reads the arguments of a command up to the closing parenthesis.
+fn arguments(chars: &mut std::iter::Peekable<std::str::Chars>) -> Vec<Argument> {
+    let mut arguments = vec![];
+    let mut depth = 0;
+    while let Some(&c) = chars.peek() {
+        match c {
+            '#' => skip_comment(chars),
+            '(' => {
+                depth += 1;
+                chars.next();
+            }
+            ')' => {
+                chars.next();
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            }
+            '"' => {
+                chars.next();
+                let mut argument = String::new();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => argument.extend(chars.next()),
+                        c => argument.push(c),
+                    }
+                }
+                arguments.push(Argument {
+                    text: argument,
+                    quoted: true,
+                });
+            }
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut argument = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || matches!(c, '(' | ')' | '"' | '#') {
+                        break;
+                    }
+                    argument.push(c);
+                    chars.next();
+                }
+                arguments.push(Argument {
+                    text: argument,
+                    quoted: false,
+                });
+            }
+        }
+    }
+    arguments
+}
+
+/// Skips a `#` line comment or a `#[[...]]` bracket comment.
+fn skip_comment(chars: &mut std::iter::Peekable<std::str::Chars>) {
+    chars.next();
+    let mut opening = String::new();
+    if chars.peek() == Some(&'[') {
+        opening.push(chars.next().unwrap());
+        while chars.peek() == Some(&'=') {
+            opening.push(chars.next().unwrap());
+        }
+        if chars.peek() == Some(&'[') {
+            chars.next();
+            let closing = format!("]{}]", &opening[1..]);
+            let mut tail = String::new();
+            for c in chars.by_ref() {
+                tail.push(c);
+                if tail.ends_with(&closing) {
+                    return;
+                }
+            }
+            return;
+        }
+    }
+    for c in chars.by_ref() {
+        if c == '\n' {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_set_variables() {
+        let mut variables = Variables::new();
+        let set = |variables: &mut Variables, arguments: &[&str]| {
+            let arguments: Vec<String> = arguments.iter().map(|a| a.to_string()).collect();
+            set_variable(variables, &arguments[0], &arguments[1..]);
+        };
+        set(&mut variables, &["NAME", "core"]);
+        set(&mut variables, &["LIST", "a.cpp", "b.cpp"]);
+        set(&mut variables, &["CACHED", "x", "CACHE", "STRING", "doc"]);
+        set(&mut variables, &["PARENT", "p", "PARENT_SCOPE"]);
+        set(&mut variables, &["UNSET", "u"]);
+        set(&mut variables, &["UNSET"]);
+        let cases: &[(&str, &str)] = &[
+            ("${NAME}", "core"),
+            ("lib_${NAME}_${NAME}", "lib_core_core"),
+            ("${LIST}", "a.cpp;b.cpp"),
+            ("${CACHED}", "x"),
+            ("${PARENT}", "${PARENT}"),
+            ("${UNSET}", "${UNSET}"),
+            (
+                "${CMAKE_CURRENT_SOURCE_DIR}/a.cpp",
+                "${CMAKE_CURRENT_SOURCE_DIR}/a.cpp",
+            ),
+            ("${NAME", "${NAME"),
+        ];
+        for (argument, expected) in cases {
+            assert_eq!(expand_variables(argument, &variables), *expected);
+        }
+
+        // lists split into arguments only where unquoted
+        let cases: &[(&str, &[&str])] = &[
+            ("f(${LIST})", &["a.cpp", "b.cpp"]),
+            ("f(\"${LIST}\")", &["a.cpp;b.cpp"]),
+            ("f(a;b \"c;d\")", &["a", "b", "c;d"]),
+            ("f(a;;b; \"\")", &["a", "b", ""]),
+            ("f(${NAME} \"${NAME} x\")", &["core", "core x"]),
+        ];
+        for (command, expected) in cases {
+            let (_, arguments) = commands(command).pop().unwrap();
+            assert_eq!(
+                expand_arguments(&arguments, &variables),
+                *expected,
+                "{}",
+                command
+            );
+        }
+    }
+}
@@ -0,0 +1,516 @@
+use crate::diagnostics::{Category, Diagnostic, Diagnostics};
+use crate::preprocessor::Macros;
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Compiler settings of a translation unit that affect include resolution.
+#[derive(Clone)]
+pub struct CompileSettings {
+    /// directories given by `-iquote`, searched before `include_paths`
+    pub quote_paths: Vec<String>,
+    /// directories given by `-I`, `-isystem` and `-idirafter`, in search order
+    pub include_paths: Vec<String>,
+    pub macros: Macros,
+}
+
+/// The settings of each (canonicalized) source file. A file has several settings if it is
+/// compiled more than once, like for different targets.
+pub type CompileCommands = HashMap<String, Vec<CompileSettings>>;
+
+/// An entry of compile_commands.json, which gives either `command` or `arguments`.
+#[derive(serde::Deserialize)]
+struct CompileCommand {
+    directory: String,
+    file: String,
+    command: Option<String>,
+    arguments: Option<Vec<String>>,
+}
+
+impl CompileCommand {
+    /// Returns the arguments of the command, with response files expanded.
+    fn arguments(&self, diagnostics: &Diagnostics) -> Vec<String> {
+        let arguments = match (&self.arguments, &self.command) {
+            (Some(arguments), _) => arguments.clone(),
+            (None, Some(command)) => {
+                let compiler = command.split_whitespace().next().unwrap_or("");
+                split_command(command, uses_windows_rules(compiler))
+            }
+            (None, None) => vec![],
+        };
+        let windows_rules = uses_windows_rules(arguments.first().map_or("", String::as_str));
+        let mut expanded = vec![];
+        expand_response_files(
+            arguments,
+            Path::new(&self.directory),
+            windows_rules,
+            0,
+            &mut expanded,
+            diagnostics,
+        );
+        expanded
+    }
+}
+
+/// MSVC command lines follow the Windows rules, where backslashes are path separators and only
+/// escape quotes. Everything else follows POSIX shell quoting.
+pub fn uses_windows_rules(compiler: &str) -> bool {
+    cfg!(windows) || is_msvc(compiler)
+}
+
+/// Response files can include further response files, but not indefinitely.
+const MAX_RESPONSE_FILE_DEPTH: usize = 16;
+
+/// Replaces `@file` arguments by the arguments in that file, recursively.
+/// Relative response file paths are resolved against `directory`.
+fn expand_response_files(
+    arguments: Vec<String>,
+    directory: &Path,
+    windows_rules: bool,
+    depth: usize,
+    expanded: &mut Vec<String>,
+    diagnostics: &Diagnostics,
+) {
+    for argument in arguments {
+        let file = match argument.strip_prefix('@') {
+            Some(file) if depth < MAX_RESPONSE_FILE_DEPTH => directory.join(file),
+            _ => {
+                expanded.push(argument);
+                continue;
+            }
+        };
+        match std::fs::read(&file) {
+            Ok(bytes) => {
+                let contents = String::from_utf8_lossy(&bytes);
+                let contents = contents.trim_start_matches('\u{feff}');
+                let arguments = split_command(contents, windows_rules);
+                expand_response_files(
+                    arguments,
+                    directory,
+                    windows_rules,
+                    depth + 1,
+                    expanded,
+                    diagnostics,
+                );
+            }
+            Err(e) => {
+                // like the compiler, keep the argument as it is
+                diagnostics.report(
+                    Diagnostic::new(
+                        Category::UnreadableFile,
+                        format!("failed to read response file: {}", e),
+                    )
+                    .file(&file.to_string_lossy()),
+                );
+                expanded.push(argument);
+            }
+        }
+    }
+}
+
+/// Splits a shell-escaped command line into arguments.
+pub fn split_command(command: &str, windows_rules: bool) -> Vec<String> {
+    let mut arguments = vec![];
+    let mut current = String::new();
+    let mut in_argument = false;
+    let mut quote: Option<char> = None;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if windows_rules => {
+                // 2n backslashes before a quote become n backslashes and the quote is a
+                // delimiter, 2n+1 backslashes become n backslashes and a literal quote
+                let mut count = 1;
+                while chars.peek() == Some(&'\\') {
+                    chars.next();
+                    count += 1;
+                }
+                if chars.peek() == Some(&'"') {
+                    current.extend(std::iter::repeat_n('\\', count / 2));
+                    if count % 2 == 1 {
+                        current.push('"');
+                        chars.next();
+                    }
+                } else {
+                    current.extend(std::iter::repeat_n('\\', count));
+                }
+                in_argument = true;
+            }
+            '\\' if quote != Some('\'') => {
+                match chars.next() {
+                    // inside double quotes, a backslash only escapes a few characters
+                    Some(next) if quote == Some('"') && !matches!(next, '"' | '\\' | '$' | '`') => {
+                        current.push('\\');
+                        current.push(next);
+                    }
+                    // a continued line, which doesn't start an argument
+                    Some('\n') => continue,
+                    Some(next) => current.push(next),
+                    None => current.push('\\'),
+                }
+                in_argument = true;
+            }
+            '"' | '\'' if quote == Some(c) => quote = None,
+            '"' | '\'' if quote.is_none() && (c == '"' || !windows_rules) => {
+                quote = Some(c);
+                in_argument = true;
+            }
+            c if c.is_whitespace() && quote.is_none() => {
+                if in_argument {
+                    arguments.push(std::mem::take(&mut current));
+                    in_argument = false;
+                }
+            }
+            c => {
+                current.push(c);
+                in_argument = true;
+            }
+        }
+    }
+    if in_argument {
+        arguments.push(current);
+    }
+    arguments
+}
+
+pub fn is_msvc(compiler: &str) -> bool {
+    let name = compiler
+        .rsplit(['/', '\\'])
+        .next()
+        .unwrap_or(compiler)
+        .to_lowercase();
+    let name = name.trim_end_matches(".exe");
+    name == "cl" || name == "clang-cl"
+}
+
+/// Source file extensions that compile as C++ by default.
+const CPLUSPLUS_EXTENSIONS: [&str; 5] = [".cpp", ".cc", ".cxx", ".c++", ".mm"];
+
+/// Whether a command line compiles C++ rather than C, going by the name of the compiler, an
+/// explicit language option or the extension of the source file.
+fn is_cplusplus(arguments: &[String]) -> bool {
+    let compiler = arguments.first().map(String::as_str).unwrap_or("");
+    let name = compiler.rsplit(['/', '\\']).next().unwrap_or(compiler);
+    if name.contains("++") {
+        return true;
+    }
+    arguments.iter().enumerate().skip(1).any(|(i, argument)| {
+        let argument = argument.as_str();
+        argument == "-xc++"
+            || (argument == "-x" && arguments.get(i + 1).map(String::as_str) == Some("c++"))
+            || (is_msvc(compiler) && (argument == "/TP" || argument == "-TP"))
+            || CPLUSPLUS_EXTENSIONS.iter().any(|e| argument.ends_with(e))
+    })
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Flag {
+    Include,
+    Quote,
+    System,
+    After,
+    Define,
+    Undefine,
+}
+
+/// GCC and Clang options that take a value, either glued to the option or as the next argument.
+/// Long options are glued with `=`. Longer spellings come first, so that prefixes match last.
+const GCC_FLAGS: [(&str, Flag); 11] = [
+    ("--include-directory-after", Flag::After),
+    ("--include-directory", Flag::Include),
+    ("--define-macro", Flag::Define),
+    ("--undefine-macro", Flag::Undefine),
+    ("-idirafter", Flag::After),
+    ("-isystem", Flag::System),
+    ("-iquote", Flag::Quote),
+    ("-imsvc", Flag::System),
+    ("-I", Flag::Include),
+    ("-D", Flag::Define),
+    ("-U", Flag::Undefine),
+];
+
+/// Additional options of MSVC and clang-cl, which also accept the `-` spellings of `/` options.
+const MSVC_FLAGS: [(&str, Flag); 10] = [
+    ("/external:I", Flag::System),
+    ("-external:I", Flag::System),
+    ("/imsvc", Flag::System),
+    ("/I", Flag::Include),
+    ("/D", Flag::Define),
+    ("/U", Flag::Undefine),
+    ("-imsvc", Flag::System),
+    ("-I", Flag::Include),
+    ("-D", Flag::Define),
+    ("-U", Flag::Undefine),
+];
+
+/// Extracts the include paths and macros from the arguments of a compiler invocation.
+/// Relative include paths are resolved against `directory`.
+pub fn parse_arguments(arguments: &[String], directory: &Path) -> CompileSettings {
+    let compiler = arguments.first().map(String::as_str).unwrap_or("");
+    let flags: &[(&str, Flag)] = if is_msvc(compiler) {
+        &MSVC_FLAGS
+    } else {
+        &GCC_FLAGS
+    };
+
+    let mut paths: Vec<(Flag, String)> = vec![];
+    let mut macros = Macros::new();
+    macros.add_predefined(compiler, is_cplusplus(arguments));
+
+    let mut i = 1;
+    while i < arguments.len() {
+        let argument = arguments[i].as_str();
+        i += 1;
+        if argument == "-undef" || argument == "-I-" {
+            continue;
+        }
+        let matched = flags.iter().find_map(|&(spelling, flag)| {
+            let rest = argument.strip_prefix(spelling)?;
+            if spelling.starts_with("--") && !rest.is_empty() {
+                rest.strip_prefix('=').map(|value| (flag, Some(value)))
+            } else if rest.is_empty() {
+                Some((flag, None))
+            } else {
+                Some((flag, Some(rest)))
+            }
+        });
+        let (flag, value) = match matched {
+            Some((flag, Some(value))) => (flag, value),
+            Some((flag, None)) => match arguments.get(i) {
+                Some(value) => {
+                    i += 1;
+                    (flag, value.as_str())
+                }
+                None => break,
+            },
+            None => continue,
+        };
+        match flag {
+            Flag::Define => macros.define_from_command_line(value),
+            Flag::Undefine => macros.undefine(value),
+            _ => {
+                let path = directory.join(value).canonicalize();
+                if let Some(path) = path
+                    .ok()
+                    .and_then(|p| p.into_os_string().into_string().ok())
+                {
+                    paths.push((flag, path));
+                }
+            }
+        }
+    }
+
+    let paths_of = |flag: Flag| {
+        paths
+            .iter()
+            .filter(move |(f, _)| *f == flag)
+            .map(|(_, p)| p.clone())
+    };
+    CompileSettings {
+        quote_paths: paths_of(Flag::Quote).collect(),
+        include_paths: paths_of(Flag::Include)
+            .chain(paths_of(Flag::System))
+            .chain(paths_of(Flag::After))
+            .collect(),
+        macros,
+    }
+}
+
+/// Loads compile_commands.json, returning the settings for each (canonicalized) source file.
+pub fn load(path: &str, diagnostics: &Diagnostics) -> std::io::Result<CompileCommands> {
+    let f = std::fs::File::open(path)?;
+    let commands: Vec<CompileCommand> = serde_json::from_reader(std::io::BufReader::new(f))?;
+
+    let commands: Vec<(String, CompileSettings)> = commands
+        .into_par_iter()
+        .filter_map(|c| {
+            let file_name = Path::new(&c.directory).join(&c.file);
+            let file_name = match file_name.canonicalize() {
+                Ok(path) => path,
+                Err(e) => {
+                    diagnostics.report(
+                        Diagnostic::new(
+                            Category::UnknownSource,
+                            format!("source file of a compile command not found: {}", e),
+                        )
+                        .file(&file_name.to_string_lossy()),
+                    );
+                    return None;
+                }
+            };
+            let settings = parse_arguments(&c.arguments(diagnostics), Path::new(&c.directory));
+            Some((file_name.to_string_lossy().into_owned(), settings))
+        })
+        .collect();
+
+    let mut settings: CompileCommands = HashMap::new();
+    for (file_name, s) in commands {
+        settings.entry(file_name).or_default().push(s);
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::preprocessor;
+
+    fn strings(arguments: &[&str]) -> Vec<String> {
+        arguments.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn split_posix_commands() {
+        let cases: &[(&str, &[&str])] = &[
+            ("g++ -c  a.cpp", &["g++", "-c", "a.cpp"]),
+            (
+                r#"g++ "-DNAME=\"x y\"" a.cpp"#,
+                &["g++", r#"-DNAME="x y""#, "a.cpp"],
+            ),
+            (r#"g++ '-DA=\n' "b\n""#, &["g++", r"-DA=\n", r"b\n"]),
+            (r"g++ -Idir\ with\ space", &["g++", "-Idir with space"]),
+            (r"g++ -Ia\\b", &["g++", r"-Ia\b"]),
+            ("g++ '' -c", &["g++", "", "-c"]),
+            ("g++ -I\"a\"'b'c", &["g++", "-Iabc"]),
+            ("g++ \\\n -c", &["g++", "-c"]),
+        ];
+        for (command, expected) in cases {
+            assert_eq!(
+                split_command(command, false),
+                strings(expected),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn split_windows_commands() {
+        let cases: &[(&str, &[&str])] = &[
+            (
+                r"cl /IC:\src\include a.cpp",
+                &["cl", r"/IC:\src\include", "a.cpp"],
+            ),
+            (
+                r#"cl "/IC:\Program Files\x" a.cpp"#,
+                &["cl", r"/IC:\Program Files\x", "a.cpp"],
+            ),
+            (r#"cl /D"A=\"1\"""#, &["cl", r#"/DA="1""#]),
+            (r#"cl "C:\dir\\" b"#, &["cl", r"C:\dir\", "b"]),
+            (r#"cl a\\\"b"#, &["cl", r#"a\"b"#]),
+            (r"cl '/Ia b'", &["cl", "'/Ia", "b'"]),
+        ];
+        for (command, expected) in cases {
+            assert_eq!(
+                split_command(command, true),
+                strings(expected),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn parse_include_flags() {
+        let directory = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let src = directory.join("src").canonicalize().unwrap();
+        let src = src.to_str().unwrap();
+        let tests = directory.join("tests").canonicalize().unwrap();
+        let tests = tests.to_str().unwrap();
+        // arguments, quote paths, include paths
+        let cases: &[(&[&str], &[&str], &[&str])] = &[
+            (&["g++", "-Isrc", "-I", "tests"], &[], &[src, tests]),
+            (
+                &["g++", "-iquote", "src", "-isystem", "tests"],
+                &[src],
+                &[tests],
+            ),
+            (&["g++", "-idirafter", "src", "-Itests"], &[], &[tests, src]),
+            (&["g++", "--include-directory=src"], &[], &[src]),
+            (&["g++", "--include-directory", "src"], &[], &[src]),
+            (
+                &["g++", "--include-directory-after=src", "-Itests"],
+                &[],
+                &[tests, src],
+            ),
+            (&["g++", "-Imissing", "-Isrc"], &[], &[src]),
+            (&["g++", "-I-", "-undef", "-Isrc"], &[], &[src]),
+            (&["cl", "/Isrc", "-external:I", "tests"], &[], &[src, tests]),
+            (
+                &["clang-cl", "-imsvc", "src", "/external:Itests"],
+                &[],
+                &[src, tests],
+            ),
+            (&["g++", "/Isrc"], &[], &[]),
+            (&["g++", "-I"], &[], &[]),
+        ];
+        for (arguments, quote_paths, include_paths) in cases {
+            let settings = parse_arguments(&strings(arguments), directory);
+            assert_eq!(
+                settings.quote_paths,
+                strings(quote_paths),
+                "{:?}",
+                arguments
+            );
+            assert_eq!(
+                settings.include_paths,
+                strings(include_paths),
+                "{:?}",
+                arguments
+            );
+        }
+    }
+
+    #[test]
+    fn parse_macro_flags() {
+        // arguments, expression, value
+        let cases: &[(&[&str], &str, Option<bool>)] = &[
+            (&["g++", "-DA"], "A == 1", Some(true)),
+            (&["g++", "-D", "A=2"], "A == 2", Some(true)),
+            (&["g++", "--define-macro=A=3"], "A == 3", Some(true)),
+            (&["g++", "--define-macro", "A"], "defined A", Some(true)),
+            (&["g++", "-DA", "-UA"], "defined A", Some(false)),
+            (
+                &["g++", "-DA", "--undefine-macro=A"],
+                "defined A",
+                Some(false),
+            ),
+            (&["cl", "/DA=4", "-DB"], "A == 4 && B", Some(true)),
+            (&["cl", "/DA", "/UA"], "defined A", Some(false)),
+            (&["g++", "-D__linux__=0"], "__linux__", Some(false)),
+        ];
+        for (arguments, expression, expected) in cases {
+            let settings = parse_arguments(&strings(arguments), Path::new("."));
+            assert_eq!(
+                preprocessor::evaluate(expression, &settings.macros),
+                *expected,
+                "{:?}",
+                arguments
+            );
+        }
+    }
+
+    #[test]
+    fn detect_cplusplus() {
+        let cases: &[(&[&str], bool)] = &[
+            (&["/usr/bin/g++", "-c", "a.c"], true),
+            (&["clang++"], true),
+            (&["gcc", "-c", "a.c"], false),
+            (&["gcc", "-c", "a.cpp"], true),
+            (&["cc", "-c", "a.cc"], true),
+            (&["gcc", "-x", "c++", "a.h"], true),
+            (&["gcc", "-xc++", "a.h"], true),
+            (&["cl", "/TP", "a.c"], true),
+            (&["cl", "a.c"], false),
+            (&["C:\\VS\\cl.exe", "a.cxx"], true),
+        ];
+        for (arguments, expected) in cases {
+            assert_eq!(
+                is_cplusplus(&strings(arguments)),
+                *expected,
+                "{:?}",
+                arguments
+            );
+        }
+    }
+}
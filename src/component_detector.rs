@@ -0,0 +1,45 @@
+//! Detection of component directories from the build files they contain.
+
+use std::str::FromStr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ComponentDetector {
+    CMake,
+    Bazel,
+    Meson,
+    MsBuild,
+    Make,
+}
+
+impl ComponentDetector {
+    /// Whether a file with this name makes its directory a component.
+    pub fn is_marker(self, file_name: &str) -> bool {
+        match self {
+            ComponentDetector::CMake => file_name == "CMakeLists.txt",
+            ComponentDetector::Bazel => file_name == "BUILD" || file_name == "BUILD.bazel",
+            ComponentDetector::Meson => file_name == "meson.build",
+            ComponentDetector::MsBuild => file_name.ends_with(".vcxproj"),
+            ComponentDetector::Make => {
+                matches!(file_name, "Makefile" | "makefile" | "GNUmakefile")
+            }
+        }
+    }
+}
+
+impl FromStr for ComponentDetector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ComponentDetector, String> {
+        match s {
+            "cmake" => Ok(ComponentDetector::CMake),
+            "bazel" => Ok(ComponentDetector::Bazel),
+            "meson" => Ok(ComponentDetector::Meson),
+            "msbuild" => Ok(ComponentDetector::MsBuild),
+            "make" => Ok(ComponentDetector::Make),
+            _ => Err(format!(
+                "unknown component detector {}, expected cmake, bazel, meson, msbuild or make",
+                s
+            )),
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! The component manifest: named components that claim files by glob patterns, wherever the files
+//! are in the tree.
+
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+use std::io;
+
+pub struct ManifestComponent {
+    pub name: String,
+    /// the directory all patterns of the component start with, relative to the root
+    pub path: String,
+    files: GlobSet,
+}
+
+pub struct Manifest {
+    pub components: Vec<ManifestComponent>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ManifestFile {
+    components: Vec<ComponentEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ComponentEntry {
+    name: String,
+    files: Vec<String>,
+}
+
+impl Manifest {
+    /// Reads a JSON file like `{"components": [{"name": "core", "files": ["include/core/**"]}]}`.
+    /// Patterns are relative to the root; `*` stays within a directory, `**` crosses them.
+    pub fn load(path: &str) -> io::Result<Manifest> {
+        Manifest::parse(&std::fs::read(path)?)
+    }
+
+    fn parse(json: &[u8]) -> io::Result<Manifest> {
+        let file: ManifestFile = serde_json::from_slice(json)?;
+        let mut components: Vec<ManifestComponent> = vec![];
+        for entry in file.components {
+            if components.iter().any(|c| c.name == entry.name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("component {} is listed twice", entry.name),
+                ));
+            }
+            let mut files = GlobSetBuilder::new();
+            for pattern in &entry.files {
+                files.add(glob(pattern)?);
+            }
+            components.push(ManifestComponent {
+                path: common_directory(&entry.files),
+                name: entry.name,
+                files: files.build().map_err(invalid_data)?,
+            });
+        }
+        Ok(Manifest { components })
+    }
+
+    /// Returns the components whose patterns match a root-relative path, in manifest order.
+    pub fn claims(&self, path: &str) -> Vec<usize> {
+        self.components
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| c.files.is_match(path))
+            .map(|(i, _)| i)
+            .collect()
+    }
+}
+
+fn glob(pattern: &str) -> io::Result<Glob> {
+    GlobBuilder::new(pattern.trim_start_matches("./"))
+        .literal_separator(true)
+        .build()
+        .map_err(invalid_data)
+}
+
+fn invalid_data(e: globset::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// The longest directory that is a literal prefix of every pattern.
+fn common_directory(patterns: &[String]) -> String {
+    let literal_directory = |pattern: &str| -> Vec<String> {
+        let mut segments: Vec<String> = pattern
+            .trim_start_matches("./")
+            .split('/')
+            .map(str::to_string)
+            .collect();
+        // the last segment names files
+        segments.pop();
+        segments
+            .into_iter()
+            .take_while(|s| !s.contains(&['*', '?', '[', '{'][..]))
+            .collect()
+    };
+    let mut common = match patterns.first() {
+        Some(pattern) => literal_directory(pattern),
+        None => return String::new(),
+    };
+    for pattern in &patterns[1..] {
+        let segments = literal_directory(pattern);
+        let same = common
+            .iter()
+            .zip(&segments)
+            .take_while(|(a, b)| a == b)
+            .count();
+        common.truncate(same);
+    }
+    common.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim_files() {
+        let manifest = Manifest::parse(
+            br#"{"components": [
+                {"name": "core", "files": ["include/core/**", "src/core/*.cpp"]},
+                {"name": "api", "files": ["include/*/api.h", "./include/api/**"]},
+                {"name": "tests", "files": ["**/test_*.cpp"]},
+                {"name": "nothing", "files": []}
+            ]}"#,
+        )
+        .unwrap();
+        let cases: &[(&str, &[usize])] = &[
+            ("include/core/a.h", &[0]),
+            ("include/core/detail/a.h", &[0]),
+            ("src/core/a.cpp", &[0]),
+            // `*` stays within a directory
+            ("src/core/detail/a.cpp", &[]),
+            ("src/core/a.h", &[]),
+            ("include/api/api.h", &[1]),
+            ("include/api/other.h", &[1]),
+            // claimed by several
+            ("include/core/api.h", &[0, 1]),
+            ("src/core/test_a.cpp", &[0, 2]),
+            ("test_a.cpp", &[2]),
+            ("other/a.h", &[]),
+        ];
+        for &(path, expected) in cases {
+            assert_eq!(manifest.claims(path), expected, "{}", path);
+        }
+        let paths: Vec<&str> = manifest
+            .components
+            .iter()
+            .map(|c| c.path.as_str())
+            .collect();
+        assert_eq!(paths, ["", "include", "", ""]);
+    }
+
+    #[test]
+    fn find_common_directories() {
+        let cases: &[(&[&str], &str)] = &[
+            (&["include/core/**"], "include/core"),
+            (
+                &["include/core/**", "include/core/detail/*.h"],
+                "include/core",
+            ),
+            (&["include/core/**", "src/core/*.cpp"], ""),
+            (&["include/core/a.h", "include/api/a.h"], "include"),
+            (&["./lib/*.h"], "lib"),
+            (&["lib/*/x/*.h"], "lib"),
+            (&["lib/{a,b}/*.h"], "lib"),
+            (&["a.h"], ""),
+            (&[], ""),
+        ];
+        for &(patterns, expected) in cases {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            assert_eq!(common_directory(&patterns), expected, "{:?}", patterns);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_manifests() {
+        let cases: &[&[u8]] = &[
+            br#"{"components": [{"name": "a", "files": ["a/[*"]}]}"#,
+            br#"{"components": [{"name": "a"}]}"#,
+            br#"{"components": [{"name": "a", "files": [], "path": "a"}]}"#,
+            br#"{"components": [{"name": "a", "files": []}, {"name": "a", "files": []}]}"#,
+            br#"{"components": "#,
+        ];
+        for json in cases {
+            assert!(
+                Manifest::parse(json).is_err(),
+                "{}",
+                String::from_utf8_lossy(json)
+            );
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Reading of Makefile-style `.d` depfiles, as written by GCC and Clang with `-MD`.
+
+use crate::diagnostics::{Category, Diagnostic, Diagnostics};
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+
+/// The files a translation unit was compiled from, as recorded by the build.
+pub struct Dependencies {
+    pub source: PathBuf,
+    /// all headers included by `source`, directly or transitively
+    pub headers: Vec<PathBuf>,
+}
+
+/// Reads all depfiles below `dir`. Relative paths in depfiles are resolved against `dir`.
+pub fn load(dir: &str, diagnostics: &Diagnostics) -> Vec<Dependencies> {
+    let dir = Path::new(dir);
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let depfiles: Vec<PathBuf> = ignore::WalkBuilder::new(&dir)
+        .standard_filters(false)
+        .build()
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.into_path()),
+            Err(e) => {
+                diagnostics.report(Diagnostic::new(
+                    Category::UnreadableFile,
+                    format!("failed to read directory entry: {}", e),
+                ));
+                None
+            }
+        })
+        .filter(|path| path.extension().map(|e| e == "d").unwrap_or(false))
+        .collect();
+
+    depfiles
+        .into_par_iter()
+        .filter_map(|path| match std::fs::read(&path) {
+            Ok(bytes) => {
+                let mut prerequisites = parse(&String::from_utf8_lossy(&bytes)).into_iter();
+                let source = dir.join(prerequisites.next()?);
+                let headers = prerequisites.map(|p| dir.join(p)).collect();
+                Some(Dependencies { source, headers })
+            }
+            Err(e) => {
+                diagnostics.report(
+                    Diagnostic::new(
+                        Category::UnreadableFile,
+                        format!("failed to read depfile: {}", e),
+                    )
+                    .file(&path.to_string_lossy()),
+                );
+                None
+            }
+        })
+        .collect()
+}
+
+/// Returns the prerequisites of the first rule in a depfile.
+fn parse(contents: &str) -> Vec<String> {
+    let mut prerequisites = vec![];
+    let mut word = String::new();
+    let mut in_prerequisites = false;
+    let mut chars = contents.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.peek() {
+                Some(' ') | Some('#') => word.push(chars.next().unwrap()),
+                Some('\n') | Some('\r') => {
+                    // line continuation
+                    chars.next();
+                    if chars.peek() == Some(&'\n') {
+                        chars.next();
+                    }
+                    finish_word(&mut word, in_prerequisites, &mut prerequisites);
+                }
+                _ => word.push(c),
+            },
+            '$' if chars.peek() == Some(&'$') => {
+                chars.next();
+                word.push('$');
+            }
+            // the colon after the targets, as opposed to the one in a drive letter
+            ':' if !in_prerequisites && chars.peek().map(|c| c.is_whitespace()).unwrap_or(true) => {
+                word.clear();
+                in_prerequisites = true;
+            }
+            '\n' if in_prerequisites => {
+                finish_word(&mut word, in_prerequisites, &mut prerequisites);
+                break;
+            }
+            c if c.is_whitespace() => finish_word(&mut word, in_prerequisites, &mut prerequisites),
+            c => word.push(c),
+        }
+    }
+    finish_word(&mut word, in_prerequisites, &mut prerequisites);
+    prerequisites
+}
+
+fn finish_word(word: &mut String, in_prerequisites: bool, prerequisites: &mut Vec<String>) {
+    if in_prerequisites && !word.is_empty() {
+        prerequisites.push(word.clone());
+    }
+    word.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_prerequisites() {
+        let cases: &[(&str, &[&str])] = &[
+            ("a.o: a.cpp a.h\n", &["a.cpp", "a.h"]),
+            ("a.o: a.cpp \\\n  a.h \\\n  b.h\n", &["a.cpp", "a.h", "b.h"]),
+            ("a.o: a.cpp \\\r\n  a.h\r\n", &["a.cpp", "a.h"]),
+            (
+                "a.o: dir\\ with\\ space/a.cpp a\\#b.h\n",
+                &["dir with space/a.cpp", "a#b.h"],
+            ),
+            ("a.o: a$$b.cpp\n", &["a$b.cpp"]),
+            (
+                "a.o: C:\\src\\a.cpp C:/include/a.h\n",
+                &["C:\\src\\a.cpp", "C:/include/a.h"],
+            ),
+            ("C:\\build\\a.o: a.cpp\n", &["a.cpp"]),
+            ("a.o b.o: a.cpp\n", &["a.cpp"]),
+            ("a.o: a.cpp a.h\na.h:\n", &["a.cpp", "a.h"]),
+            ("a.o:\n", &[]),
+            ("a.o: a.cpp", &["a.cpp"]),
+            ("", &[]),
+        ];
+        for (contents, expected) in cases {
+            assert_eq!(parse(contents), *expected, "{:?}", contents);
+        }
+    }
+}
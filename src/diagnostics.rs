@@ -0,0 +1,185 @@
+//! Problems found while loading a graph, like includes that lead nowhere or files that could not
+//! be read. They are collected rather than printed, so that front ends can filter and present
+//! them.
+
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+use std::sync::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Note,
+    Warning,
+    Error,
+}
+
+impl Severity {
+    pub fn name(self) -> &'static str {
+        match self {
+            Severity::Note => "note",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Category {
+    /// an include that names no file of the tree
+    MissingInclude,
+    /// an include with `.` or `..` that names no file of the tree, or a computed include whose
+    /// expansion is no include argument
+    MalformedInclude,
+    /// an include of a macro, which is only resolved with compile commands
+    ComputedInclude,
+    /// an include that the include paths resolve to a file outside of the tree
+    OutsideInclude,
+    /// a file whose text encoding could not be detected
+    Encoding,
+    /// a dependency recorded by the build that the scanned includes disagree with
+    Mismatch,
+    /// a source file that the build knows of, but that is not in the tree
+    UnknownSource,
+    /// a file or directory that could not be read
+    UnreadableFile,
+    /// a file that several components of the manifest claim
+    ManifestConflict,
+    /// a CMake target whose name uses variables that are not understood
+    UnresolvedTarget,
+}
+
+impl Category {
+    pub const ALL: [Category; 10] = [
+        Category::MissingInclude,
+        Category::MalformedInclude,
+        Category::ComputedInclude,
+        Category::OutsideInclude,
+        Category::Encoding,
+        Category::Mismatch,
+        Category::UnknownSource,
+        Category::UnreadableFile,
+        Category::ManifestConflict,
+        Category::UnresolvedTarget,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::MissingInclude => "missing-include",
+            Category::MalformedInclude => "malformed-include",
+            Category::ComputedInclude => "computed-include",
+            Category::OutsideInclude => "outside-include",
+            Category::Encoding => "encoding",
+            Category::Mismatch => "mismatch",
+            Category::UnknownSource => "unknown-source",
+            Category::UnreadableFile => "unreadable-file",
+            Category::ManifestConflict => "manifest-conflict",
+            Category::UnresolvedTarget => "unresolved-target",
+        }
+    }
+
+    pub fn severity(self) -> Severity {
+        match self {
+            Category::ComputedInclude | Category::OutsideInclude | Category::Encoding => {
+                Severity::Note
+            }
+            Category::UnreadableFile => Severity::Error,
+            _ => Severity::Warning,
+        }
+    }
+
+    /// Whether the diagnostics of this category are shown without asking for them. The others
+    /// are common in trees that are fine, like missing includes of system headers.
+    pub fn shown_by_default(self) -> bool {
+        matches!(
+            self,
+            Category::OutsideInclude
+                | Category::UnknownSource
+                | Category::UnreadableFile
+                | Category::ManifestConflict
+                | Category::UnresolvedTarget
+        )
+    }
+}
+
+impl FromStr for Category {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Category, String> {
+        Category::ALL
+            .iter()
+            .find(|c| c.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = Category::ALL.iter().map(|c| c.name()).collect();
+                format!(
+                    "unknown diagnostic category {}, expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// the file the diagnostic is about, root-relative for files of the tree
+    pub file: Option<String>,
+    pub line: Option<usize>,
+    pub category: Category,
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new(category: Category, message: String) -> Diagnostic {
+        Diagnostic {
+            file: None,
+            line: None,
+            category,
+            severity: category.severity(),
+            message,
+        }
+    }
+
+    pub fn file(mut self, file: &str) -> Diagnostic {
+        self.file = Some(file.to_string());
+        self
+    }
+
+    pub fn line(mut self, line: usize) -> Diagnostic {
+        self.line = Some(line);
+        self
+    }
+
+    /// Where the diagnostic is, as `path:line`, `path`, or empty if it is about no file.
+    pub fn location(&self) -> String {
+        match (&self.file, self.line) {
+            (Some(file), Some(line)) => format!("{}:{}", file, line),
+            (Some(file), None) => file.clone(),
+            (None, _) => String::new(),
+        }
+    }
+}
+
+/// Collects diagnostics from the threads that load a graph.
+#[derive(Default)]
+pub struct Diagnostics {
+    list: Mutex<Vec<Diagnostic>>,
+}
+
+impl Diagnostics {
+    pub fn report(&self, diagnostic: Diagnostic) {
+        self.list.lock().unwrap().push(diagnostic);
+    }
+
+    /// The diagnostics ordered by file and line, each once. Threads report in no particular
+    /// order, and cached include resolution may report the same problem more than once.
+    pub fn into_sorted(self) -> Vec<Diagnostic> {
+        let mut list = self.list.into_inner().unwrap();
+        list.sort();
+        list.dedup();
+        list
+    }
+}
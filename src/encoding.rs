@@ -0,0 +1,151 @@
+//! Detection of the text encoding of source files.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Encoding {
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// the fallback for files that are neither UTF-8 nor UTF-16, i.e. the encoding could not be
+    /// decided
+    Latin1,
+}
+
+/// How many bytes the UTF-16 heuristic looks at.
+const SAMPLE_SIZE: usize = 4096;
+
+/// Detects the encoding of `bytes` from the byte order mark, or else from the bytes themselves,
+/// and decodes them.
+pub fn decode(bytes: &[u8]) -> (String, Encoding) {
+    if let Some(rest) = bytes.strip_prefix(b"\xef\xbb\xbf") {
+        return (String::from_utf8_lossy(rest).into_owned(), Encoding::Utf8);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xff\xfe") {
+        return (decode_utf16(rest, u16::from_le_bytes), Encoding::Utf16Le);
+    }
+    if let Some(rest) = bytes.strip_prefix(b"\xfe\xff") {
+        return (decode_utf16(rest, u16::from_be_bytes), Encoding::Utf16Be);
+    }
+    if let Some(encoding) = guess_utf16(bytes) {
+        let text = match encoding {
+            Encoding::Utf16Le => decode_utf16(bytes, u16::from_le_bytes),
+            _ => decode_utf16(bytes, u16::from_be_bytes),
+        };
+        return (text, encoding);
+    }
+    match std::str::from_utf8(bytes) {
+        Ok(text) => (text.to_string(), Encoding::Utf8),
+        Err(_) => (bytes.iter().map(|&b| b as char).collect(), Encoding::Latin1),
+    }
+}
+
+/// Decodes UTF-16, replacing invalid units and a trailing odd byte with U+FFFD.
+fn decode_utf16(bytes: &[u8], from_bytes: fn([u8; 2]) -> u16) -> String {
+    let chunks = bytes.chunks_exact(2);
+    let truncated = !chunks.remainder().is_empty();
+    let units: Vec<u16> = chunks.map(|c| from_bytes([c[0], c[1]])).collect();
+    let mut text = String::from_utf16_lossy(&units);
+    if truncated {
+        text.push(char::REPLACEMENT_CHARACTER);
+    }
+    text
+}
+
+/// Source code is mostly ASCII, which in UTF-16 without a byte order mark shows up as a NUL in
+/// every other byte: the odd ones for little endian, the even ones for big endian.
+fn guess_utf16(bytes: &[u8]) -> Option<Encoding> {
+    let sample = &bytes[..bytes.len().min(SAMPLE_SIZE)];
+    let pairs = sample.len() / 2;
+    if pairs == 0 {
+        return None;
+    }
+    let even_nuls = sample.iter().step_by(2).filter(|&&b| b == 0).count();
+    let odd_nuls = sample
+        .iter()
+        .skip(1)
+        .step_by(2)
+        .filter(|&&b| b == 0)
+        .count();
+    if odd_nuls * 2 > pairs && even_nuls * 8 < pairs {
+        Some(Encoding::Utf16Le)
+    } else if even_nuls * 2 > pairs && odd_nuls * 8 < pairs {
+        Some(Encoding::Utf16Be)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utf16(text: &str, to_bytes: fn(u16) -> [u8; 2]) -> Vec<u8> {
+        text.encode_utf16().flat_map(to_bytes).collect()
+    }
+
+    #[test]
+    fn decode_encodings() {
+        let le = |text| utf16(text, u16::to_le_bytes);
+        let be = |text| utf16(text, u16::to_be_bytes);
+        let with_bom = |bom: &[u8], bytes: Vec<u8>| [bom, &bytes].concat();
+        let long = "#include <a.h>\n".repeat(SAMPLE_SIZE);
+        let cases: Vec<(Vec<u8>, &str, Encoding)> = vec![
+            (b"#include <a.h>".to_vec(), "#include <a.h>", Encoding::Utf8),
+            ("// \u{e9}\n".into(), "// \u{e9}\n", Encoding::Utf8),
+            (b"".to_vec(), "", Encoding::Utf8),
+            (
+                with_bom(b"\xef\xbb\xbf", b"#include <a.h>".to_vec()),
+                "#include <a.h>",
+                Encoding::Utf8,
+            ),
+            (
+                with_bom(b"\xff\xfe", le("#include <\u{e9}.h>")),
+                "#include <\u{e9}.h>",
+                Encoding::Utf16Le,
+            ),
+            (
+                with_bom(b"\xfe\xff", be("#include <\u{e9}.h>")),
+                "#include <\u{e9}.h>",
+                Encoding::Utf16Be,
+            ),
+            // without a byte order mark, by the NULs
+            (le("#include <a.h>"), "#include <a.h>", Encoding::Utf16Le),
+            (be("#include <a.h>"), "#include <a.h>", Encoding::Utf16Be),
+            (le(&long), &long, Encoding::Utf16Le),
+            // a single NUL in ASCII is no reason for UTF-16
+            (b"a\0bcdefgh".to_vec(), "a\0bcdefgh", Encoding::Utf8),
+            // neither UTF-8 nor UTF-16
+            (
+                b"// \xe9t\xe9".to_vec(),
+                "// \u{e9}t\u{e9}",
+                Encoding::Latin1,
+            ),
+            // odd lengths
+            (
+                [le("#include"), vec![b'x']].concat(),
+                "#include\u{fffd}",
+                Encoding::Utf16Le,
+            ),
+            (
+                with_bom(b"\xfe\xff", [be("ab"), vec![0]].concat()),
+                "ab\u{fffd}",
+                Encoding::Utf16Be,
+            ),
+            (b"\xff\xfe\x61".to_vec(), "\u{fffd}", Encoding::Utf16Le),
+        ];
+        for (bytes, text, encoding) in &cases {
+            assert_eq!(decode(bytes), (text.to_string(), *encoding), "{:?}", bytes);
+        }
+    }
+
+    #[test]
+    fn guess_utf16_from_sample() {
+        // only the sample is looked at: UTF-8 text after it does not matter
+        let mut bytes = utf16(&"a".repeat(SAMPLE_SIZE / 2), u16::to_le_bytes);
+        bytes.extend_from_slice("\u{e9}".repeat(SAMPLE_SIZE).as_bytes());
+        assert_eq!(guess_utf16(&bytes), Some(Encoding::Utf16Le));
+        assert_eq!(guess_utf16(b"a"), None);
+        assert_eq!(guess_utf16(b""), None);
+    }
+}
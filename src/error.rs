@@ -0,0 +1,95 @@
+//! The errors of loading and querying a graph.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug)]
+pub enum Error {
+    /// A file or directory could not be read, written or parsed.
+    Io {
+        /// what was done with the file, like "read compile commands"
+        operation: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// A graph file could not be decoded, or a graph not encoded.
+    Bincode {
+        operation: &'static str,
+        path: PathBuf,
+        source: bincode::Error,
+    },
+    /// A path that is not valid UTF-8, which the graph cannot hold.
+    NonUtf8Path(PathBuf),
+    /// A command line that cannot work, beyond what the argument parser checks.
+    Usage(&'static str),
+    /// A component, module or file named in a query that the graph does not have.
+    NotFound { kind: &'static str, name: String },
+    /// The terminal UI could not draw or read input.
+    Terminal(io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl Error {
+    /// Whether the error is in how cpdep was called rather than in the files it read.
+    pub fn is_usage_error(&self) -> bool {
+        matches!(self, Error::Usage(_) | Error::NotFound { .. })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io {
+                operation,
+                path,
+                source,
+            } => write!(f, "failed to {} {}: {}", operation, path.display(), source),
+            Error::Bincode {
+                operation,
+                path,
+                source,
+            } => write!(f, "failed to {} {}: {}", operation, path.display(), source),
+            Error::NonUtf8Path(path) => write!(f, "path is not valid UTF-8: {}", path.display()),
+            Error::Usage(message) => write!(f, "{}", message),
+            Error::NotFound { kind, name } => write!(f, "{} not found: {}", kind, name),
+            Error::Terminal(source) => write!(f, "terminal UI failed: {}", source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Terminal(source) => Some(source),
+            Error::Bincode { source, .. } => Some(source),
+            Error::NonUtf8Path(_) | Error::Usage(_) | Error::NotFound { .. } => None,
+        }
+    }
+}
+
+/// Adds the operation and the file to the error of a result.
+pub trait Context<T> {
+    fn context(self, operation: &'static str, path: impl AsRef<Path>) -> Result<T>;
+}
+
+impl<T> Context<T> for io::Result<T> {
+    fn context(self, operation: &'static str, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| Error::Io {
+            operation,
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
+}
+
+impl<T> Context<T> for bincode::Result<T> {
+    fn context(self, operation: &'static str, path: impl AsRef<Path>) -> Result<T> {
+        self.map_err(|source| Error::Bincode {
+            operation,
+            path: path.as_ref().to_path_buf(),
+            source,
+        })
+    }
+}
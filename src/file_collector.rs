@@ -1,40 +1,60 @@
+use crate::component_detector::ComponentDetector;
+use crate::diagnostics::{Category, Diagnostic, Diagnostics};
+use crate::encoding::{self, Encoding};
+use crate::error::Error;
+use crate::language::LanguageProfile;
+use crate::scan_cache::{FileStamp, ScanCache};
+use crate::scanner::{self, Directive, Include, ModuleDeclaration, ModuleImport, Scan};
 use ignore::{DirEntry, ParallelVisitor, ParallelVisitorBuilder, WalkState};
-use lazy_static::lazy_static;
 use serde::{Deserialize, Serialize};
 use std::io::{self, Read};
 use std::path::Path;
 use std::sync::{Arc, Mutex};
 
-lazy_static! {
-    static ref INCLUDE_RE: regex::bytes::Regex =
-        regex::bytes::Regex::new("#\\s*include\\s*[<\"]([^>\"]+)").unwrap();
-    static ref INCLUDE_RE_16: regex::bytes::Regex =
-        regex::bytes::Regex::new("#\0[\\s\0]*i\0n\0c\0l\0u\0d\0e\0[\\s\0]*[<\"]\0([^>\"]+)")
-            .unwrap();
-}
-
-pub fn read_files(root: &str, options: &crate::Opt) -> FileCollector {
+pub fn read_files(
+    root: &str,
+    profile: &LanguageProfile,
+    detectors: &[ComponentDetector],
+    cache: &ScanCache,
+    diagnostics: &Diagnostics,
+) -> FileCollector {
     let root_path = root.replace('\\', "/");
     let root_path = root_path.trim_end_matches('/');
 
     let collector = Arc::new(Mutex::new(FileCollector {
         files: vec![],
+        stamps: vec![],
+        cached_files: 0,
         components: vec![],
     }));
 
     let mut builder = FileCollectorBuilder {
         root: root_path.to_owned(),
-        warn_malformed: options.warn_malformed,
+        profile: Arc::new(profile.clone()),
+        detectors: detectors.to_vec(),
+        cache,
+        diagnostics,
         file_collector: collector,
     };
 
-    ignore::WalkBuilder::new(root_path.to_owned())
+    ignore::WalkBuilder::new(root_path)
         .threads(6)
         .build_parallel()
         .visit(&mut builder);
 
     let lock = std::sync::Arc::try_unwrap(builder.file_collector).unwrap();
     let mut base_project = lock.into_inner().unwrap();
+
+    // of several markers in one directory, the first detector given wins
+    let priority = |c: &Component| {
+        let marker = c.marker.as_deref().unwrap_or("");
+        detectors.iter().position(|d| d.is_marker(marker))
+    };
+    base_project
+        .components
+        .sort_by(|a, b| (&a.path, priority(a)).cmp(&(&b.path, priority(b))));
+    base_project.components.dedup_by(|a, b| a.path == b.path);
+
     if base_project
         .components
         .iter()
@@ -43,6 +63,8 @@ pub fn read_files(root: &str, options: &crate::Opt) -> FileCollector {
     {
         base_project.components.push(Component {
             path: String::new(),
+            marker: None,
+            name: None,
         });
     }
     base_project
@@ -51,22 +73,46 @@ pub fn read_files(root: &str, options: &crate::Opt) -> FileCollector {
 #[derive(Debug)]
 pub struct FileCollector {
     pub files: Vec<File>,
+    /// the size and modification time of each of `files` when it was scanned
+    pub stamps: Vec<Option<FileStamp>>,
+    /// how many of `files` were taken from the scan cache
+    pub cached_files: usize,
     pub components: Vec<Component>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct File {
     pub path: String,
-    pub include_paths: Vec<String>,
+    pub includes: Vec<Include>,
+    /// conditionals, defines and includes in source order, see `preprocessor::walk`
+    pub directives: Vec<Directive>,
+    /// the C++20 module this file belongs to
+    pub module: Option<ModuleDeclaration>,
+    pub imports: Vec<ModuleImport>,
+    pub encoding: Encoding,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Component {
     pub path: String,
+    /// the name of the file that made the directory a component, `None` for the root directory
+    /// without one
+    pub marker: Option<String>,
+    /// the CMake target or manifest entry, for components that hold the files it names rather
+    /// than a directory, as `dir:name` if another component has the same name
+    pub name: Option<String>,
 }
 
 impl Component {
+    /// Orders components by directory, and named components within a directory by name.
+    pub fn sort_key(&self) -> (&str, Option<&str>) {
+        (&self.path, self.name.as_deref())
+    }
+
     pub fn nice_name(&self) -> &str {
+        if let Some(name) = &self.name {
+            return name;
+        }
         if self.path.is_empty() {
             return ".";
         }
@@ -74,116 +120,137 @@ impl Component {
     }
 }
 
-struct FileCollectorBuilder {
+struct FileCollectorBuilder<'a> {
     root: String,
-    warn_malformed: bool,
+    profile: Arc<LanguageProfile>,
+    detectors: Vec<ComponentDetector>,
+    cache: &'a ScanCache,
+    diagnostics: &'a Diagnostics,
     file_collector: Arc<Mutex<FileCollector>>,
 }
 
-impl<'a, 's> ParallelVisitorBuilder<'s> for FileCollectorBuilder {
+impl<'s> ParallelVisitorBuilder<'s> for FileCollectorBuilder<'s> {
     fn build(&mut self) -> Box<dyn ignore::ParallelVisitor + 's> {
         Box::new(FileCollectorThread {
             root: self.root.clone(),
-            warn_malformed: self.warn_malformed,
+            profile: self.profile.clone(),
+            detectors: self.detectors.clone(),
+            cache: self.cache,
+            diagnostics: self.diagnostics,
             files: vec![],
+            stamps: vec![],
+            cached_files: 0,
             components: vec![],
             parent: self.file_collector.clone(),
         })
     }
 }
 
-struct FileCollectorThread {
+struct FileCollectorThread<'a> {
     root: String,
-    warn_malformed: bool,
+    profile: Arc<LanguageProfile>,
+    detectors: Vec<ComponentDetector>,
+    cache: &'a ScanCache,
+    diagnostics: &'a Diagnostics,
     files: Vec<File>,
+    stamps: Vec<Option<FileStamp>>,
+    cached_files: usize,
     components: Vec<Component>,
     parent: Arc<Mutex<FileCollector>>,
 }
 
-impl FileCollectorThread {
+impl FileCollectorThread<'_> {
     fn rel_path<'a>(&self, path: &'a str) -> &'a str {
         path.trim_start_matches(&self.root).trim_start_matches('/')
     }
 }
 
-impl Drop for FileCollectorThread {
+impl Drop for FileCollectorThread<'_> {
     fn drop(&mut self) {
         let mut parent = self.parent.lock().unwrap();
         parent.files.append(&mut self.files);
+        parent.stamps.append(&mut self.stamps);
+        parent.cached_files += self.cached_files;
         parent.components.append(&mut self.components);
     }
 }
 
-impl ParallelVisitor for FileCollectorThread {
+impl ParallelVisitor for FileCollectorThread<'_> {
     fn visit(&mut self, entry: Result<DirEntry, ignore::Error>) -> WalkState {
-        let source_suffixes = [
-            ".cpp", ".hpp", ".c", ".h", ".inl", ".hh", ".cc", ".ipp", ".imp", ".impl", ".H",
-        ];
         match entry {
             Ok(entry) => {
-                let path_str = entry
-                    .path()
-                    .to_str()
-                    .expect("failed to parse file name")
-                    .replace('\\', "/");
-                if entry.path().ends_with("CMakeLists.txt") {
-                    let path = path_str.trim_end_matches("/CMakeLists.txt");
+                let path_str = match entry.path().to_str() {
+                    Some(path) => path.replace('\\', "/"),
+                    None => {
+                        self.diagnostics.report(Diagnostic::new(
+                            Category::UnreadableFile,
+                            Error::NonUtf8Path(entry.into_path()).to_string(),
+                        ));
+                        // nothing below it can be named either
+                        return WalkState::Skip;
+                    }
+                };
+                let file_name = entry.file_name().to_str().unwrap_or("");
+                let is_file = entry.file_type().map(|t| t.is_file()).unwrap_or(false);
+                if is_file && self.detectors.iter().any(|d| d.is_marker(file_name)) {
+                    let path = &path_str[..path_str.rfind('/').unwrap_or(0)];
                     let path = self.rel_path(path).to_string();
-                    self.components.push(Component { path });
-                } else if source_suffixes.iter().any(|s| path_str.ends_with(s)) {
-                    match extract_includes(&entry.path(), self.warn_malformed) {
-                        Ok(include_paths) => {
-                            let path = self.rel_path(&path_str).to_string();
+                    self.components.push(Component {
+                        path,
+                        marker: Some(file_name.to_string()),
+                        name: None,
+                    });
+                } else if self.profile.file_kind(&path_str).is_some() {
+                    let path = self.rel_path(&path_str).to_string();
+                    let stamp = entry.metadata().ok().and_then(|m| FileStamp::of(&m));
+                    if let Some(file) = stamp.and_then(|s| self.cache.take(&path, s)) {
+                        self.files.push(file);
+                        self.stamps.push(stamp);
+                        self.cached_files += 1;
+                        return WalkState::Continue;
+                    }
+                    match extract_includes(entry.path()) {
+                        Ok((scan, encoding)) => {
+                            self.stamps.push(stamp);
                             self.files.push(File {
                                 path,
-                                include_paths,
+                                includes: scan.includes,
+                                directives: scan.directives,
+                                module: scan.module,
+                                imports: scan.imports,
+                                encoding,
                             })
                         }
-                        Err(e) => println!("Error while parsing {}: {}", path_str, e),
+                        Err(e) => self.diagnostics.report(
+                            Diagnostic::new(
+                                Category::UnreadableFile,
+                                format!("failed to read file: {}", e),
+                            )
+                            .file(&path),
+                        ),
                     }
                 }
             }
-            Err(e) => {
-                println!("Failed to parse file: {}", e);
-            }
+            Err(e) => self.diagnostics.report(Diagnostic::new(
+                Category::UnreadableFile,
+                format!("failed to read directory entry: {}", e),
+            )),
         }
         WalkState::Continue
     }
 }
 
-fn extract_includes(path: &Path, warn_malformed: bool) -> io::Result<Vec<String>> {
-    let mut results = Vec::new();
+fn extract_includes(path: &Path) -> io::Result<(Scan, Encoding)> {
     let mut f = std::fs::File::open(path)?;
     let mut bytes = Vec::new();
     f.read_to_end(&mut bytes)?;
 
-    for cap in INCLUDE_RE.captures_iter(&bytes) {
-        let mut include = String::from_utf8_lossy(&cap[1]).replace('\\', "/");
-        if let Some(idx) = include.rfind("../") {
-            if warn_malformed {
-                println!("malformed include in {:?}: {}", path, include);
-            }
-            include = include.split_off(idx + 3);
-        }
-        results.push(include);
-    }
+    let (text, encoding) = encoding::decode(&bytes);
+    let mut scan = scanner::scan(&text);
 
-    if results.is_empty() {
-        for cap in INCLUDE_RE_16.captures_iter(&bytes) {
-            let include_bytes: Vec<u16> = cap[1]
-                .chunks_exact(2)
-                .map(|a| u16::from_ne_bytes([a[0], a[1]]))
-                .collect();
-            let mut include = String::from_utf16_lossy(&include_bytes).replace('\\', "/");
-            if let Some(idx) = include.rfind("../") {
-                if warn_malformed {
-                    println!("malformed include in {:?}: {}", path, include);
-                }
-                include = include.split_off(idx + 3);
-            }
-            results.push(include);
-        }
+    for include in &mut scan.includes {
+        include.path = include.path.replace('\\', "/");
     }
 
-    Ok(results)
+    Ok((scan, encoding))
 }
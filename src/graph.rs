@@ -1,10 +1,27 @@
-use crate::file_collector::{self, Component, File};
-use crate::Opt;
+use crate::cmake_file_api;
+use crate::cmake_targets::{self, Target};
+use crate::compile_commands::{self, CompileCommands, CompileSettings};
+pub use crate::component_detector::ComponentDetector;
+use crate::component_manifest::Manifest;
+use crate::depfile::{self, Dependencies};
+use crate::diagnostics::{Category, Diagnostic, Diagnostics};
+use crate::encoding::Encoding;
+use crate::error::{Context, Error, Result};
+use crate::file_collector;
+pub use crate::file_collector::{Component, File};
+use crate::include_log::{self, IncludeTree};
+use crate::language::FileKind;
+pub use crate::language::LanguageProfile;
+use crate::ninja_deps;
+use crate::preprocessor::{self, Macros};
+use crate::scan_cache::{FileStamp, Resolution, ScanCache};
+use crate::scanner::{self, Include, IncludeDirective, IncludeKind};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::io::Write;
-use std::path::PathBuf;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::path::{self, Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 #[derive(Serialize, Deserialize)]
 pub struct Graph {
@@ -14,59 +31,342 @@ pub struct Graph {
     pub component_files: Vec<Vec<FileRef>>,
     pub file_links: Vec<FileLinks>,
     pub file_is_public: Vec<bool>,
+    pub modules: Vec<Module>,
+    pub module_links: Vec<ModuleLinks>,
+    pub profile: LanguageProfile,
+    /// the problems found while loading, ordered by file and line
+    pub diagnostics: Vec<Diagnostic>,
+    /// the stamp of each file when it was scanned, to rescan only changed files on a reload
+    #[serde(skip)]
+    pub(crate) stamps: Vec<Option<FileStamp>>,
+    /// what the includes of each file resolved to, to resolve only affected files on a reload
+    #[serde(skip)]
+    resolutions: Vec<Option<Resolution>>,
 }
 
 #[derive(Clone, Default, Serialize, Deserialize)]
 pub struct FileLinks {
     pub incoming_links: Vec<FileRef>,
     pub outgoing_links: Vec<FileRef>,
+    /// for each outgoing link, where it comes from
+    pub outgoing_origins: Vec<LinkOrigin>,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct LinkOrigin {
+    /// The translation units in which the link was seen, if only some of the translation units
+    /// that include the file have it. Empty if all of them have it.
+    pub translation_units: Vec<FileRef>,
+    /// index into the includes of the including file, if the link comes from a scanned include
+    pub include: Option<usize>,
+}
+
+/// A C++20 module, or a partition of one.
+#[derive(Serialize, Deserialize)]
+pub struct Module {
+    pub name: String,
+    /// the files that declare the module, empty for modules that are imported but not scanned
+    pub units: Vec<FileRef>,
+}
+
+/// Links between modules, from the imports of their units.
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct ModuleLinks {
+    pub incoming_links: Vec<ModuleRef>,
+    pub outgoing_links: Vec<ModuleRef>,
 }
 
 pub type ComponentRef = usize;
 pub type FileRef = usize;
+pub type ModuleRef = usize;
 
 pub struct Edge {
     pub from: FileRef,
     pub to: FileRef,
+    /// index into the includes of `from`, see `LinkOrigin::include`
+    pub include: Option<usize>,
 }
 
-pub fn load(options: &crate::Opt) -> Graph {
-    if let Some(root) = &options.root {
-        let base_project = file_collector::read_files(&root, &options);
-        let file_components = files_to_components(&base_project);
-        let mut component_files = vec![vec![]; base_project.components.len()];
-        for (i, &c) in file_components.iter().enumerate() {
-            component_files[c].push(i);
-        }
-        let file_links = if let Some(path) = &options.compile_commands {
-            println!("loading compile commands...");
-            std::io::stdout().flush().unwrap();
-            let compile_commands = load_compile_commands(&path).unwrap();
-            println!("loading file dependencies...");
-            std::io::stdout().flush().unwrap();
-            generate_file_links_from_commands(
-                &base_project.files,
-                &compile_commands,
-                &root,
-                &options,
-            )
-        } else {
-            generate_file_links(&base_project.files, &file_components, &options)
+/// Where the links between files come from.
+#[derive(Clone, Debug)]
+pub enum LinkSource {
+    /// the scanned includes, matched to files by the end of their path
+    Includes,
+    /// the include paths and defines of each translation unit in a compile_commands.json
+    CompileCommands(String),
+    /// the targets, sources and include paths in the CMake File API reply of a build directory
+    CMakeReply(String),
+    /// the .ninja_deps log in a build directory
+    NinjaDeps(String),
+    /// the .d depfiles in a build directory
+    Depfiles(String),
+    /// a build log with MSVC /showIncludes or Clang -H output
+    IncludeLog(String),
+}
+
+/// How to load the graph of a source tree:
+///
+/// ```no_run
+/// use cpdep::graph::{LinkSource, LoadOptions};
+///
+/// let graph = LoadOptions::new("src")
+///     .links(LinkSource::CompileCommands("build/compile_commands.json".into()))
+///     .cmake_targets(true)
+///     .load()?;
+/// # Ok::<(), cpdep::error::Error>(())
+/// ```
+#[derive(Clone, Debug)]
+pub struct LoadOptions {
+    pub(crate) root: String,
+    pub(crate) links: LinkSource,
+    pub(crate) component_detectors: Vec<ComponentDetector>,
+    pub(crate) cmake_targets: bool,
+    pub(crate) component_manifest: Option<String>,
+    pub(crate) language_profile: Option<String>,
+    pub(crate) scan_cache: Option<String>,
+    pub(crate) progress: Option<Progress>,
+}
+
+/// Receives the steps of loading a graph, like "loading compile commands...".
+#[derive(Clone)]
+pub(crate) struct Progress(Arc<dyn Fn(&str) + Send + Sync>);
+
+impl fmt::Debug for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+impl LoadOptions {
+    /// Scans the files below `root` and links them by their includes, with a component for each
+    /// directory that has a `CMakeLists.txt`.
+    pub fn new(root: &str) -> LoadOptions {
+        LoadOptions {
+            root: root.to_string(),
+            links: LinkSource::Includes,
+            component_detectors: vec![ComponentDetector::CMake],
+            cmake_targets: false,
+            component_manifest: None,
+            language_profile: None,
+            scan_cache: None,
+            progress: None,
+        }
+    }
+
+    pub fn links(mut self, links: LinkSource) -> LoadOptions {
+        self.links = links;
+        self
+    }
+
+    /// The build files that make a directory a component. Of several in one directory, the
+    /// first detector given wins.
+    pub fn component_detectors(mut self, detectors: Vec<ComponentDetector>) -> LoadOptions {
+        self.component_detectors = detectors;
+        self
+    }
+
+    /// Assigns files to the CMake targets that list them instead of to directories. A CMake
+    /// reply as the link source brings its own targets.
+    pub fn cmake_targets(mut self, cmake_targets: bool) -> LoadOptions {
+        self.cmake_targets = cmake_targets;
+        self
+    }
+
+    /// A JSON file of named components and the glob patterns of their files, see
+    /// `component_manifest`.
+    pub fn component_manifest(mut self, path: &str) -> LoadOptions {
+        self.component_manifest = Some(path.to_string());
+        self
+    }
+
+    /// A JSON file with additional file suffixes, see `LanguageProfile::load`.
+    pub fn language_profile(mut self, path: &str) -> LoadOptions {
+        self.language_profile = Some(path.to_string());
+        self
+    }
+
+    /// A file to keep the scanned files in, so that only changed files are scanned again.
+    pub fn scan_cache(mut self, path: &str) -> LoadOptions {
+        self.scan_cache = Some(path.to_string());
+        self
+    }
+
+    /// Calls `progress` with a message for each step of loading, which takes a while for large
+    /// trees. Without it, loading is silent.
+    pub fn progress(mut self, progress: impl Fn(&str) + Send + Sync + 'static) -> LoadOptions {
+        self.progress = Some(Progress(Arc::new(progress)));
+        self
+    }
+
+    fn report_progress(&self, message: &str) {
+        if let Some(Progress(progress)) = &self.progress {
+            progress(message);
+        }
+    }
+
+    pub fn load(&self) -> Result<Graph> {
+        let profile = match &self.language_profile {
+            Some(path) => LanguageProfile::load(path).context("read language profile", path)?,
+            None => LanguageProfile::default(),
+        };
+        let cache = match &self.scan_cache {
+            Some(path) => ScanCache::load(path, &self.root, &profile),
+            None => ScanCache::default(),
         };
-        let file_is_public = generate_is_public(&file_links, &file_components);
+        load_root(self, profile, cache)
+    }
+}
 
-        Graph {
-            files: base_project.files,
-            components: base_project.components,
-            file_components,
-            component_files,
-            file_links,
-            file_is_public,
+/// Loads the graph of the files below the root, taking the scans of unchanged files from `cache`.
+fn load_root(options: &LoadOptions, profile: LanguageProfile, cache: ScanCache) -> Result<Graph> {
+    let root = &options.root;
+    std::fs::read_dir(root).context("read root", root)?;
+    let diagnostics = Diagnostics::default();
+    let mut base_project = file_collector::read_files(
+        root,
+        &profile,
+        &options.component_detectors,
+        &cache,
+        &diagnostics,
+    );
+    if options.scan_cache.is_some() {
+        options.report_progress(&format!(
+            "{} of {} files unchanged since the last scan",
+            base_project.cached_files,
+            base_project.files.len()
+        ));
+    }
+    for file in &base_project.files {
+        if file.encoding == Encoding::Latin1 {
+            diagnostics.report(
+                Diagnostic::new(
+                    Category::Encoding,
+                    "encoding could not be detected, read as Latin-1".to_string(),
+                )
+                .file(&file.path),
+            );
+        }
+    }
+    let (reply_targets, reply_settings) = match &options.links {
+        LinkSource::CMakeReply(dir) => {
+            options.report_progress("loading cmake reply...");
+            let (targets, settings) =
+                cmake_file_api::load(dir, root).context("read CMake File API reply in", dir)?;
+            (Some(targets), settings)
         }
+        _ => (None, HashMap::new()),
+    };
+    let mut file_owners = if let Some(targets) = &reply_targets {
+        add_target_components(targets, &mut base_project)
+    } else if options.cmake_targets {
+        let targets = cmake_targets::load(root, &base_project.components, &diagnostics);
+        add_target_components(&targets, &mut base_project)
     } else {
-        let encoded = std::fs::read(options.import.as_ref().unwrap()).unwrap();
-        bincode::deserialize(&encoded).unwrap()
+        HashMap::new()
+    };
+    if let Some(path) = &options.component_manifest {
+        let manifest = Manifest::load(path).context("read component manifest", path)?;
+        let marker = Path::new(path)
+            .file_name()
+            .and_then(|name| name.to_str())
+            .unwrap_or(path);
+        add_manifest_components(
+            &manifest,
+            marker,
+            &mut base_project,
+            &mut file_owners,
+            &diagnostics,
+        );
     }
+    let mut file_components = files_to_components(&base_project, &file_owners);
+    make_names_distinct(&mut base_project.components, &mut file_components);
+    let mut component_files = vec![vec![]; base_project.components.len()];
+    for (i, &c) in file_components.iter().enumerate() {
+        component_files[c].push(i);
+    }
+    let mut resolutions = vec![];
+    let file_links = match &options.links {
+        LinkSource::Includes => {
+            let (file_links, resolved) =
+                generate_file_links(&base_project.files, &file_components, &cache, &diagnostics);
+            resolutions = resolved;
+            file_links
+        }
+        LinkSource::CMakeReply(_) => {
+            options.report_progress("loading file dependencies...");
+            generate_file_links_from_commands(
+                &base_project.files,
+                &reply_settings,
+                root,
+                &diagnostics,
+            )?
+        }
+        LinkSource::CompileCommands(path) => {
+            options.report_progress("loading compile commands...");
+            let compile_commands = compile_commands::load(path, &diagnostics)
+                .context("read compile commands", path)?;
+            options.report_progress("loading file dependencies...");
+            generate_file_links_from_commands(
+                &base_project.files,
+                &compile_commands,
+                root,
+                &diagnostics,
+            )?
+        }
+        LinkSource::NinjaDeps(dir) => {
+            options.report_progress("loading ninja deps...");
+            let dependencies = ninja_deps::load(dir).context("read ninja deps in", dir)?;
+            generate_file_links_from_dependencies(
+                &base_project.files,
+                &dependencies,
+                root,
+                &diagnostics,
+            )?
+        }
+        LinkSource::Depfiles(dir) => {
+            options.report_progress("loading depfiles...");
+            let dependencies = depfile::load(dir, &diagnostics);
+            generate_file_links_from_dependencies(
+                &base_project.files,
+                &dependencies,
+                root,
+                &diagnostics,
+            )?
+        }
+        LinkSource::IncludeLog(path) => {
+            options.report_progress("loading include log...");
+            let trees = include_log::load(path, &profile).context("read include log", path)?;
+            generate_file_links_from_include_trees(&base_project.files, &trees, root, &diagnostics)?
+        }
+    };
+    let file_is_public = generate_is_public(&file_links, &file_components);
+    let (modules, module_links) = generate_modules(&base_project.files);
+    if let Some(path) = &options.scan_cache {
+        ScanCache::save(
+            path,
+            root,
+            &profile,
+            &base_project.files,
+            &base_project.stamps,
+            &resolutions,
+        )
+        .context("write scan cache", path)?;
+    }
+
+    Ok(Graph {
+        files: base_project.files,
+        components: base_project.components,
+        file_components,
+        component_files,
+        file_links,
+        file_is_public,
+        modules,
+        module_links,
+        profile,
+        diagnostics: diagnostics.into_sorted(),
+        stamps: base_project.stamps,
+        resolutions,
+    })
 }
 
 impl Graph {
@@ -106,8 +406,7 @@ impl Graph {
     }*/
 
     pub fn is_header(&self, file_ref: FileRef) -> bool {
-        let path = &self.files[file_ref].path;
-        path.ends_with(".h") || path.ends_with(".hpp") || path.ends_with("hxx")
+        self.profile.file_kind(&self.files[file_ref].path) == Some(FileKind::Header)
     }
 
     /*fn is_source_file(&self, file_ref: FileRef) -> bool {
@@ -115,12 +414,20 @@ impl Graph {
         path.ends_with(".cpp") || path.ends_with(".c")
     }*/
 
-    pub fn component_name_to_ref(&self, component_from: &str) -> Option<ComponentRef> {
+    pub fn module_name_to_ref(&self, name: &str) -> Result<ModuleRef> {
+        self.modules
+            .iter()
+            .position(|m| m.name == name)
+            .ok_or_else(|| not_found("module", name))
+    }
+
+    pub fn component_name_to_ref(&self, component_from: &str) -> Result<ComponentRef> {
         self.components
             .iter()
             .enumerate()
             .find(|(_i, c)| c.nice_name() == component_from)
             .map(|(i, _)| i)
+            .ok_or_else(|| not_found("component", component_from))
     }
 
     pub fn linked_components(
@@ -138,10 +445,7 @@ impl Graph {
                 if !only_public || self.file_is_public[fi] {
                     let co = self.file_components[fi];
                     if co != c {
-                        incoming
-                            .entry(co)
-                            .or_default()
-                            .push(Edge { from: fi, to: f })
+                        incoming.entry(co).or_default().push(self.edge(fi, f))
                     }
                 }
             }
@@ -149,10 +453,7 @@ impl Graph {
                 for &fo in self.file_links[f].outgoing_links.iter() {
                     let co = self.file_components[fo];
                     if co != c {
-                        outgoing
-                            .entry(co)
-                            .or_default()
-                            .push(Edge { from: f, to: fo })
+                        outgoing.entry(co).or_default().push(self.edge(f, fo))
                     }
                 }
             }
@@ -160,21 +461,209 @@ impl Graph {
 
         (incoming, outgoing)
     }
+
+    pub fn edge(&self, from: FileRef, to: FileRef) -> Edge {
+        let links = &self.file_links[from];
+        // outgoing links are sorted
+        let include = links
+            .outgoing_links
+            .binary_search(&to)
+            .ok()
+            .and_then(|i| links.outgoing_origins[i].include);
+        Edge { from, to, include }
+    }
+
+    /// The include directive behind an edge, if it comes from a scanned include.
+    pub fn include(&self, edge: &Edge) -> Option<&Include> {
+        Some(&self.files[edge.from].includes[edge.include?])
+    }
+
+    /// Where an edge comes from, as `path:line` of the include directive, followed by the
+    /// directive unless it is a plain `#include`, or just the path of the including file if the
+    /// directive is not known.
+    pub fn edge_location(&self, edge: &Edge) -> String {
+        let path = &self.files[edge.from].path;
+        match self.include(edge) {
+            Some(include) => match include.directive.label() {
+                Some(label) => format!("{}:{} ({})", path, include.line, label),
+                None => format!("{}:{}", path, include.line),
+            },
+            None => path.clone(),
+        }
+    }
+
+    /// Reads a graph written by `export`.
+    pub fn import(path: &str) -> Result<Graph> {
+        let encoded = std::fs::read(path).context("read graph", path)?;
+        bincode::deserialize(&encoded).context("decode graph", path)
+    }
+
+    pub fn export(&self, path: &str) -> Result<()> {
+        let encoded = bincode::serialize(self).context("encode graph for", path)?;
+        std::fs::write(path, &encoded).context("write graph", path)
+    }
+
+    /// Loads the graph again, rescanning only the files that changed since they were scanned,
+    /// and returns the components that changed. On an error the graph stays as it was.
+    pub fn reload(&mut self, options: &LoadOptions) -> Result<Vec<ComponentRef>> {
+        let cache = ScanCache::from_files(&self.files, &self.stamps, &self.resolutions);
+        self.replace(load_root(options, self.profile.clone(), cache)?)
+    }
+
+    /// Loads the graph again, rescanning all files, for when it is not known what changed.
+    pub fn reload_all(&mut self, options: &LoadOptions) -> Result<Vec<ComponentRef>> {
+        self.replace(load_root(
+            options,
+            self.profile.clone(),
+            ScanCache::default(),
+        )?)
+    }
+
+    fn replace(&mut self, graph: Graph) -> Result<Vec<ComponentRef>> {
+        let changed = graph.changed_components(self);
+        *self = graph;
+        Ok(changed)
+    }
+
+    /// Returns the components that are new, or whose files or links differ from `old`.
+    fn changed_components(&self, old: &Graph) -> Vec<ComponentRef> {
+        let old_refs: HashMap<&str, ComponentRef> = old
+            .components
+            .iter()
+            .enumerate()
+            .map(|(i, c)| (c.nice_name(), i))
+            .collect();
+        (0..self.components.len())
+            .filter(|&c| match old_refs.get(self.components[c].nice_name()) {
+                Some(&old_c) => self.component_state(c) != old.component_state(old_c),
+                None => true,
+            })
+            .collect()
+    }
+
+    fn component_state(&self, c: ComponentRef) -> ComponentState<'_> {
+        let mut files: Vec<(&str, Option<FileStamp>)> = self.component_files[c]
+            .iter()
+            .map(|&f| {
+                (
+                    self.files[f].path.as_str(),
+                    self.stamps.get(f).copied().flatten(),
+                )
+            })
+            .collect();
+        files.sort_unstable_by_key(|(path, _)| *path);
+        let (incoming, outgoing) = self.linked_components(c, false);
+        let names = |links: HashMap<ComponentRef, Vec<Edge>>| {
+            let mut names: Vec<&str> = links
+                .keys()
+                .map(|&l| self.components[l].nice_name())
+                .collect();
+            names.sort_unstable();
+            names
+        };
+        ComponentState {
+            files,
+            incoming: names(incoming),
+            outgoing: names(outgoing),
+        }
+    }
+}
+
+pub(crate) fn not_found(kind: &'static str, name: &str) -> Error {
+    Error::NotFound {
+        kind,
+        name: name.to_string(),
+    }
 }
 
-fn files_to_components(base_project: &file_collector::FileCollector) -> Vec<ComponentRef> {
+/// The files of a component with their stamps, and the components it links with, by name.
+#[derive(PartialEq)]
+struct ComponentState<'a> {
+    files: Vec<(&'a str, Option<FileStamp>)>,
+    incoming: Vec<&'a str>,
+    outgoing: Vec<&'a str>,
+}
+
+/// Adds a component for each CMake target and returns the target component of each source.
+fn add_target_components(
+    targets: &[Target],
+    base_project: &mut file_collector::FileCollector,
+) -> HashMap<String, ComponentRef> {
+    let mut file_targets = HashMap::new();
+    for target in targets {
+        let c = base_project.components.len();
+        for source in &target.sources {
+            // the first target that lists a file gets it
+            file_targets.entry(source.clone()).or_insert(c);
+        }
+        base_project.components.push(Component {
+            path: target.directory.clone(),
+            marker: Some("CMakeLists.txt".to_string()),
+            name: Some(target.name.clone()),
+        });
+    }
+    file_targets
+}
+
+/// Adds a component for each manifest entry and lets it own the files it claims, over any target
+/// that lists them. Files claimed by several entries go to the first one and are reported.
+fn add_manifest_components(
+    manifest: &Manifest,
+    marker: &str,
+    base_project: &mut file_collector::FileCollector,
+    file_owners: &mut HashMap<String, ComponentRef>,
+    diagnostics: &Diagnostics,
+) {
+    let first = base_project.components.len();
+    for component in &manifest.components {
+        base_project.components.push(Component {
+            path: component.path.clone(),
+            marker: Some(marker.to_string()),
+            name: Some(component.name.clone()),
+        });
+    }
+    for file in &base_project.files {
+        let claims = manifest.claims(&file.path);
+        if claims.len() > 1 {
+            let names: Vec<&str> = claims
+                .iter()
+                .map(|&i| manifest.components[i].name.as_str())
+                .collect();
+            diagnostics.report(
+                Diagnostic::new(
+                    Category::ManifestConflict,
+                    format!("claimed by several components: {}", names.join(", ")),
+                )
+                .file(&file.path),
+            );
+        }
+        if let Some(&i) = claims.first() {
+            file_owners.insert(file.path.clone(), first + i);
+        }
+    }
+}
+
+/// Assigns files to the manifest entry or target that claims them, or else to the closest
+/// directory component.
+fn files_to_components(
+    base_project: &file_collector::FileCollector,
+    file_owners: &HashMap<String, ComponentRef>,
+) -> Vec<ComponentRef> {
     let default_component = base_project
         .components
         .iter()
         .enumerate()
-        .find(|(_, c)| c.path.is_empty())
+        .find(|(_, c)| c.path.is_empty() && c.name.is_none())
         .map(|(i, _)| i)
-        .unwrap();
+        .expect("the file collector adds a component for the root");
 
     base_project
         .files
         .iter()
         .map(|file| {
+            if let Some(&c) = file_owners.get(&file.path) {
+                return c;
+            }
             // Iterate over prefixes of file path, to find the most specific component.
             // Assign to the most specific component.
             // Example: file path: "a/b/header.hpp"
@@ -188,7 +677,7 @@ fn files_to_components(base_project: &file_collector::FileCollector) -> Vec<Comp
                     .components
                     .iter()
                     .enumerate()
-                    .find(|(_, c)| c.path == path)
+                    .find(|(_, c)| c.path == path && c.name.is_none())
                 {
                     return i;
                 }
@@ -199,38 +688,216 @@ fn files_to_components(base_project: &file_collector::FileCollector) -> Vec<Comp
         .collect()
 }
 
-fn generate_file_links(
-    files: &[File],
-    file_components: &[ComponentRef],
-    options: &Opt,
-) -> Vec<FileLinks> {
-    // map from possible include paths to corresponding files
-    // for example: "a/b/header.h" could be included as "header.h", "b/header.h", and "a/b/header.h"
+/// Gives every component a distinct name. A directory component that lost all of its files and
+/// whose name a target or manifest entry has, like the `core` target of `core/`, is dropped,
+/// since the named component takes its place. A named component that still shares its name with
+/// another one is qualified by its directory, as in `core:core`.
+fn make_names_distinct(components: &mut Vec<Component>, file_components: &mut [ComponentRef]) {
+    let mut file_counts = vec![0usize; components.len()];
+    for &c in file_components.iter() {
+        file_counts[c] += 1;
+    }
+    let names: HashSet<&str> = components
+        .iter()
+        .filter_map(|c| c.name.as_deref())
+        .collect();
+    let keep: Vec<bool> = components
+        .iter()
+        .zip(&file_counts)
+        .map(|(c, &count)| c.name.is_some() || count > 0 || !names.contains(c.nice_name()))
+        .collect();
+    let mut new_refs = vec![0; components.len()];
+    let kept_refs = (0..components.len()).filter(|&c| keep[c]);
+    for (new_ref, c) in kept_refs.enumerate() {
+        new_refs[c] = new_ref;
+    }
+    for c in file_components.iter_mut() {
+        *c = new_refs[*c];
+    }
+    let mut kept = keep.into_iter();
+    components.retain(|_| kept.next().unwrap());
+
+    let mut name_counts: HashMap<String, usize> = HashMap::new();
+    for c in components.iter() {
+        *name_counts.entry(c.nice_name().to_string()).or_default() += 1;
+    }
+    for c in components.iter_mut() {
+        if name_counts[c.nice_name()] > 1 {
+            if let Some(name) = &c.name {
+                let directory = if c.path.is_empty() { "." } else { &c.path };
+                c.name = Some(format!("{}:{}", directory, name));
+            }
+        }
+    }
+}
+
+/// Maps possible include paths to corresponding files.
+/// For example: "a/b/header.h" could be included as "header.h", "b/header.h", and "a/b/header.h"
+fn include_paths_to_files(files: &[File]) -> HashMap<String, Vec<FileRef>> {
     // assumption here: normalized paths with unix slashes
     let mut path_to_files: HashMap<String, Vec<FileRef>> = HashMap::new();
     for (i_file, file) in files.iter().enumerate() {
-        path_to_files
-            .entry(file.path.clone())
-            .or_default()
-            .push(i_file);
-        for (idx, _) in file.path.match_indices('/') {
-            path_to_files
-                .entry(file.path[idx + 1..].into())
-                .or_default()
-                .push(i_file);
+        for key in path_keys(&file.path) {
+            path_to_files.entry(key.into()).or_default().push(i_file);
+        }
+    }
+    path_to_files
+}
+
+/// The keys a file is found by in `include_paths_to_files`: its path and each suffix of it that
+/// starts after a slash.
+fn path_keys(path: &str) -> impl Iterator<Item = &str> {
+    std::iter::once(path).chain(
+        path.match_indices('/')
+            .map(move |(idx, _)| &path[idx + 1..]),
+    )
+}
+
+/// The keys an include is looked up by in `include_paths_to_files`, see `include_candidates`:
+/// the path joined to the including file's directory, if that is tried first, and the path
+/// looked up by suffix.
+fn include_keys<'a>(file: &File, include: &'a Include) -> (Option<String>, &'a str) {
+    let joined = if include.kind == IncludeKind::Quoted
+        && include.directive != IncludeDirective::IncludeNext
+    {
+        join_include(&file.path, &include.path)
+    } else {
+        None
+    };
+    let path = match include.path.rfind("../") {
+        Some(idx) => &include.path[idx + 3..],
+        None => include.path.trim_start_matches("./"),
+    };
+    (joined, path)
+}
+
+/// Looks up the files an include may refer to. A quoted include is looked up next to the
+/// including file first, which also resolves paths like "../common/util.h". Otherwise the part
+/// after the last `../` is looked up by suffix, since it might be relative to an include directory.
+fn include_candidates<'a>(
+    path_to_files: &'a HashMap<String, Vec<FileRef>>,
+    files: &[File],
+    file: &File,
+    include: &Include,
+) -> Option<&'a [FileRef]> {
+    if include.kind == IncludeKind::Computed {
+        return None;
+    }
+    let (joined, path) = include_keys(file, include);
+    if let Some(joined) = joined {
+        if let Some(candidates) = path_to_files.get(&joined) {
+            // the exact path, not one that merely ends with it
+            if let Some(exact) = candidates.iter().find(|&&c| files[c].path == joined) {
+                return Some(std::slice::from_ref(exact));
+            }
         }
     }
+    path_to_files.get(path).map(Vec::as_slice)
+}
+
+fn is_relative_include(include: &str) -> bool {
+    include.split('/').any(|s| s == "." || s == "..")
+}
+
+/// Joins an include to the directory of the including file, both root-relative with unix
+/// slashes. Returns `None` if the result would leave the root.
+fn join_include(file_path: &str, include: &str) -> Option<String> {
+    let mut segments: Vec<&str> = file_path.split('/').collect();
+    segments.pop();
+    for segment in include.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop()?;
+            }
+            s => segments.push(s),
+        }
+    }
+    Some(segments.join("/"))
+}
+
+/// Links the files by their includes, and returns what the includes of each file resolved to.
+/// Resolutions are taken from `cache` for files whose includes cannot resolve differently, since
+/// no file was added or removed that they could refer to.
+fn generate_file_links(
+    files: &[File],
+    file_components: &[ComponentRef],
+    cache: &ScanCache,
+    diagnostics: &Diagnostics,
+) -> (Vec<FileLinks>, Vec<Option<Resolution>>) {
+    let changed_keys: HashSet<&str> = if cache.has_resolutions() {
+        let paths: HashSet<&str> = files.iter().map(|f| f.path.as_str()).collect();
+        let added = paths
+            .iter()
+            .copied()
+            .filter(|p| !cache.paths().contains(*p));
+        let removed = cache.paths().iter().map(String::as_str);
+        let removed = removed.filter(|p| !paths.contains(p));
+        added.chain(removed).flat_map(path_keys).collect()
+    } else {
+        HashSet::new()
+    };
+    let is_affected = |file: &File| {
+        file.includes
+            .iter()
+            .filter(|i| i.includes_file() && i.kind != IncludeKind::Computed)
+            .any(|include| {
+                let (joined, path) = include_keys(file, include);
+                joined.is_some_and(|j| changed_keys.contains(j.as_str()))
+                    || changed_keys.contains(path)
+            })
+    };
+    let mut path_to_files = None;
+    let resolutions: Vec<Resolution> = files
+        .iter()
+        .map(|file| {
+            let cached = cache.take_resolution(&file.path);
+            if let Some(resolution) = cached.filter(|_| !is_affected(file)) {
+                return resolution;
+            }
+            let path_to_files = path_to_files.get_or_insert_with(|| include_paths_to_files(files));
+            file.includes
+                .iter()
+                .map(|include| {
+                    let candidates = if include.includes_file() {
+                        include_candidates(path_to_files, files, file, include)
+                    } else {
+                        None
+                    };
+                    let candidates = candidates.unwrap_or_default().iter();
+                    candidates.map(|&c| files[c].path.clone()).collect()
+                })
+                .collect()
+        })
+        .collect();
+    let path_to_file: HashMap<&str, FileRef> = files
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (f.path.as_str(), i))
+        .collect();
 
-    let mut file_links = vec![FileLinks::default(); files.len()];
+    let mut edges = vec![];
 
     for (i_file, file) in files.iter().enumerate() {
-        for include in file.include_paths.iter() {
-            let deps = path_to_files.get(include);
-            if let Some(deps) = deps {
+        for (i_include, include) in file.includes.iter().enumerate() {
+            if !include.includes_file() {
+                continue;
+            }
+            let deps: Vec<FileRef> = resolutions[i_file]
+                .get(i_include)
+                .into_iter()
+                .flatten()
+                .filter_map(|path| path_to_file.get(path.as_str()).copied())
+                .collect();
+            if !deps.is_empty() {
                 let is_present_in_this_component = deps
                     .iter()
                     .any(|f| file_components[*f] == file_components[i_file]);
                 for &dep in deps.iter() {
+                    if dep == i_file {
+                        // a wrapper header that forwards with #include_next
+                        continue;
+                    }
                     if is_present_in_this_component
                         && file_components[dep] != file_components[i_file]
                     {
@@ -238,17 +905,316 @@ fn generate_file_links(
                         // This avoids adding dependencies to headers with name clashes (like StdAfx.h).
                         continue;
                     }
-                    file_links[i_file].outgoing_links.push(dep);
-                    file_links[dep].incoming_links.push(i_file);
+                    let origin = LinkOrigin {
+                        include: Some(i_include),
+                        ..LinkOrigin::default()
+                    };
+                    edges.push((i_file, dep, origin));
                 }
-            } else if options.warn_missing {
-                println!("include not found in {}: {}", file.path, include);
+            } else if include.kind == IncludeKind::Computed {
+                diagnostics.report(computed_include(file, include));
+            } else {
+                diagnostics.report(unresolved_include(file, include, &include.path));
             }
         }
     }
+    let file_links = file_links_from_edges(files.len(), edges.into_iter());
+    (file_links, resolutions.into_iter().map(Some).collect())
+}
+
+fn computed_include(file: &File, include: &Include) -> Diagnostic {
+    Diagnostic::new(
+        Category::ComputedInclude,
+        format!("computed include: {}", include.spelling),
+    )
+    .file(&file.path)
+    .line(include.line)
+}
+
+/// An include of `path` that leads to no file: malformed if it has `.` or `..` in it, missing
+/// otherwise.
+fn unresolved_include(file: &File, include: &Include, path: &str) -> Diagnostic {
+    let diagnostic = if is_relative_include(path) {
+        Diagnostic::new(
+            Category::MalformedInclude,
+            format!("malformed include: {}", path),
+        )
+    } else {
+        Diagnostic::new(
+            Category::MissingInclude,
+            format!("include not found: {}", path),
+        )
+    };
+    diagnostic.file(&file.path).line(include.line)
+}
+
+/// Reports the computed includes whose macro is not defined in the same file.
+fn report_computed_includes(files: &[File], diagnostics: &Diagnostics) {
+    for file in files {
+        for include in file
+            .includes
+            .iter()
+            .filter(|i| i.kind == IncludeKind::Computed)
+        {
+            diagnostics.report(computed_include(file, include));
+        }
+    }
+}
+
+/// Builds the links of all files from a list of edges, in a deterministic order. Of several
+/// edges between the same files, the one from the first include is kept.
+fn file_links_from_edges(
+    file_count: usize,
+    edges: impl Iterator<Item = (FileRef, FileRef, LinkOrigin)>,
+) -> Vec<FileLinks> {
+    let mut edges: Vec<(FileRef, FileRef, LinkOrigin)> = edges.collect();
+    edges.sort_unstable_by_key(|(from, to, origin)| (*from, *to, origin.include));
+    edges.dedup_by_key(|(from, to, _)| (*from, *to));
+    let mut file_links = vec![FileLinks::default(); file_count];
+    for (from, to, origin) in edges {
+        file_links[from].outgoing_links.push(to);
+        file_links[from].outgoing_origins.push(origin);
+        file_links[to].incoming_links.push(from);
+    }
     file_links
 }
 
+/// Maps paths outside of the file collector onto the files of the graph, using the same
+/// root-relative spelling.
+struct FileIndex {
+    root: PathBuf,
+    path_to_file: HashMap<String, FileRef>,
+}
+
+impl FileIndex {
+    fn new(files: &[File], root: &str) -> Result<FileIndex> {
+        let root = PathBuf::from(root)
+            .canonicalize()
+            .context("resolve root", root)?;
+        let path_to_file = files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (FileIndex::key(&f.path), i))
+            .collect();
+        Ok(FileIndex { root, path_to_file })
+    }
+
+    fn key(path: &str) -> String {
+        if cfg!(windows) {
+            path.to_lowercase()
+        } else {
+            path.to_owned()
+        }
+    }
+
+    /// Looks up an absolute path. Returns `None` for files outside of the root.
+    fn get(&self, path: &Path) -> Option<FileRef> {
+        let path = normalize(path);
+        let relative = path.strip_prefix(&self.root).ok()?;
+        let relative = relative.to_str()?.replace('\\', "/");
+        self.path_to_file.get(&FileIndex::key(&relative)).copied()
+    }
+}
+
+/// Removes `.` and `..` components without touching the file system.
+fn normalize(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            path::Component::CurDir => {}
+            path::Component::ParentDir => {
+                result.pop();
+            }
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+/// Derives direct include edges from the transitive header sets recorded by the build: an include
+/// in one of the files of a translation unit becomes an edge if it names another file of the
+/// same translation unit.
+fn generate_file_links_from_dependencies(
+    files: &[File],
+    dependencies: &[Dependencies],
+    root: &str,
+    diagnostics: &Diagnostics,
+) -> Result<Vec<FileLinks>> {
+    report_computed_includes(files, diagnostics);
+    let index = FileIndex::new(files, root)?;
+    let path_to_files = include_paths_to_files(files);
+    // a build may well leave out these, so they are no mismatch
+    let conditional: Vec<HashSet<usize>> = files
+        .par_iter()
+        .map(|f| preprocessor::conditional_includes(&f.directives))
+        .collect();
+
+    // the edges with the include they come from
+    let mut edges: HashMap<(FileRef, FileRef), usize> = HashMap::new();
+    // the mismatches are counted over the translation units, to report each once
+    let mut unit_count = 0;
+    let mut file_units: HashMap<FileRef, usize> = HashMap::new();
+    let mut unrecorded: HashMap<(FileRef, usize), usize> = HashMap::new();
+    let mut unreached: HashMap<FileRef, usize> = HashMap::new();
+    for deps in dependencies {
+        let source = match index.get(&deps.source) {
+            Some(source) => source,
+            None => continue,
+        };
+        let unit: HashSet<FileRef> = std::iter::once(source)
+            .chain(deps.headers.iter().filter_map(|h| index.get(h)))
+            .collect();
+        unit_count += 1;
+        for &f in &unit {
+            *file_units.entry(f).or_default() += 1;
+        }
+
+        let mut reached: HashSet<FileRef> = HashSet::new();
+        for &f in &unit {
+            for (i_include, include) in files[f].includes.iter().enumerate() {
+                if !include.includes_file() {
+                    continue;
+                }
+                let candidates = match include_candidates(&path_to_files, files, &files[f], include)
+                {
+                    Some(candidates) => candidates,
+                    None => continue,
+                };
+                let mut found = false;
+                for &dep in candidates.iter().filter(|&&c| c != f && unit.contains(&c)) {
+                    edges.entry((f, dep)).or_insert(i_include);
+                    reached.insert(dep);
+                    found = true;
+                }
+                if !found && !conditional[f].contains(&i_include) {
+                    *unrecorded.entry((f, i_include)).or_default() += 1;
+                }
+            }
+        }
+        for &f in unit
+            .iter()
+            .filter(|&&f| f != source && !reached.contains(&f))
+        {
+            *unreached.entry(f).or_default() += 1;
+        }
+    }
+    for ((f, i_include), count) in unrecorded {
+        let include = &files[f].includes[i_include];
+        diagnostics.report(
+            Diagnostic::new(
+                Category::Mismatch,
+                format!(
+                    "includes {}, which the build did not record in {} of {} translation units",
+                    include.path, count, file_units[&f]
+                ),
+            )
+            .file(&files[f].path)
+            .line(include.line),
+        );
+    }
+    for (f, count) in unreached {
+        diagnostics.report(
+            Diagnostic::new(
+                Category::Mismatch,
+                format!(
+                    "recorded by the build in {} of {} translation units, but not included by \
+                     any scanned file",
+                    count, unit_count
+                ),
+            )
+            .file(&files[f].path),
+        );
+    }
+
+    Ok(file_links_from_edges(
+        files.len(),
+        edges.into_iter().map(|((from, to), include)| {
+            let origin = LinkOrigin {
+                include: Some(include),
+                ..LinkOrigin::default()
+            };
+            (from, to, origin)
+        }),
+    ))
+}
+
+/// Takes the edges from include trees printed by the compiler.
+fn generate_file_links_from_include_trees(
+    files: &[File],
+    trees: &[IncludeTree],
+    root: &str,
+    diagnostics: &Diagnostics,
+) -> Result<Vec<FileLinks>> {
+    report_computed_includes(files, diagnostics);
+    let index = FileIndex::new(files, root)?;
+    let path_to_files = include_paths_to_files(files);
+    let cwd = std::env::current_dir().unwrap_or_else(|_| index.root.clone());
+
+    // Relative paths are relative to the directory the compiler ran in, which the log doesn't
+    // tell. Try the working directory, then the root.
+    let resolve = |path: &Path| {
+        index
+            .get(&cwd.join(path))
+            .or_else(|| index.get(&index.root.join(path)))
+    };
+
+    let mut edges: HashSet<(FileRef, FileRef)> = HashSet::new();
+    for tree in trees {
+        // MSVC prints only the file name of the source file
+        let source = tree.source.as_ref().and_then(|source| {
+            resolve(source).or_else(|| {
+                match path_to_files.get(&source.to_str()?.replace('\\', "/")) {
+                    Some(candidates) if candidates.len() == 1 => Some(candidates[0]),
+                    _ => None,
+                }
+            })
+        });
+        if source.is_none() {
+            let mut diagnostic = Diagnostic::new(
+                Category::UnknownSource,
+                "source file of include tree not found".to_string(),
+            );
+            if let Some(path) = &tree.source {
+                diagnostic = diagnostic.file(&path.to_string_lossy());
+            }
+            diagnostics.report(diagnostic);
+        }
+
+        // the chain of files leading to the current include, None for files outside of the root
+        let mut parents: Vec<Option<FileRef>> = vec![source];
+        for (depth, path) in &tree.includes {
+            let file = resolve(path);
+            if *depth > parents.len() {
+                // malformed nesting, the parent is unknown
+                parents.resize(*depth, None);
+            }
+            parents.truncate(*depth);
+            if let (Some(from), Some(to)) = (parents[depth - 1], file) {
+                edges.insert((from, to));
+            }
+            parents.push(file);
+        }
+    }
+
+    Ok(file_links_from_edges(
+        files.len(),
+        edges.into_iter().map(|(from, to)| {
+            // the log doesn't say which include it was, take the first one that fits
+            let include = files[from].includes.iter().position(|include| {
+                include.includes_file()
+                    && include_candidates(&path_to_files, files, &files[from], include)
+                        .map(|candidates| candidates.contains(&to))
+                        .unwrap_or(false)
+            });
+            let origin = LinkOrigin {
+                include,
+                ..LinkOrigin::default()
+            };
+            (from, to, origin)
+        }),
+    ))
+}
+
 fn to_internal_path(p: &str) -> String {
     if cfg!(windows) {
         p.replace('/', "\\")
@@ -257,120 +1223,390 @@ fn to_internal_path(p: &str) -> String {
     }
 }
 
+/// Identifies a distinct list of include paths.
+type PathSetRef = usize;
+
+/// The directories searched for includes, in order. Quoted includes search all of them, angle
+/// includes only those from `angle_start` on.
+#[derive(PartialEq, Eq, Hash)]
+struct SearchPaths {
+    paths: Vec<PathBuf>,
+    angle_start: usize,
+}
+
 fn generate_file_links_from_commands(
     files: &[File],
-    compile_commands: &HashMap<String, Vec<String>>,
+    compile_commands: &CompileCommands,
     root: &str,
-    options: &Opt,
-) -> Vec<FileLinks> {
+    diagnostics: &Diagnostics,
+) -> Result<Vec<FileLinks>> {
     use std::iter::FromIterator;
 
-    let root = PathBuf::from(&root).canonicalize().unwrap();
+    let root = PathBuf::from(&root)
+        .canonicalize()
+        .context("resolve root", root)?;
+    // the file paths are UTF-8, so with the root all paths joined to it are
+    if root.to_str().is_none() {
+        return Err(Error::NonUtf8Path(root));
+    }
 
     let path_to_id: HashMap<String, FileRef> =
         HashMap::from_iter(files.iter().enumerate().map(|(i, f)| {
             (
                 root.join(to_internal_path(&f.path))
-                    .to_str()
-                    .unwrap()
+                    .to_string_lossy()
                     .to_lowercase(),
                 i,
             )
         }));
 
-    //println!("{:?}", path_to_id.keys());
+    // translation units in file order, with their settings and include path set; a file that is
+    // compiled several times is a unit for each of its settings
+    let mut path_sets: HashMap<SearchPaths, PathSetRef> = HashMap::new();
+    let mut units: Vec<(FileRef, &CompileSettings, PathSetRef)> = vec![];
+    for (i_file, file) in files.iter().enumerate() {
+        let file_path = root.join(to_internal_path(&file.path));
+        let all_settings = match compile_commands.get(file_path.to_string_lossy().as_ref()) {
+            Some(all_settings) => all_settings,
+            None => continue,
+        };
+        for settings in all_settings {
+            let search_paths = SearchPaths {
+                paths: settings
+                    .quote_paths
+                    .iter()
+                    .chain(&settings.include_paths)
+                    .map(PathBuf::from)
+                    .collect(),
+                angle_start: settings.quote_paths.len(),
+            };
+            let next_ref = path_sets.len();
+            let path_set = *path_sets.entry(search_paths).or_insert(next_ref);
+            units.push((i_file, settings, path_set));
+        }
+    }
+    let mut path_sets: Vec<(SearchPaths, PathSetRef)> = path_sets.into_iter().collect();
+    path_sets.sort_by_key(|(_, r)| *r);
 
-    let file_links = std::sync::Mutex::new(vec![FileLinks::default(); files.len()]);
+    let resolver = IncludeResolver {
+        files,
+        root,
+        path_to_id,
+        path_sets: path_sets.into_iter().map(|(paths, _)| paths).collect(),
+        cache: RwLock::new(HashMap::new()),
+        diagnostics,
+    };
 
-    files
+    // First pass: count in how many translation units each file and each edge occurs, and note
+    // the first include each edge comes from.
+    let (visits, edge_counts) = units
+        .par_iter()
+        .fold(
+            || (vec![0u32; files.len()], HashMap::new()),
+            |(mut visits, mut edge_counts), &(unit, settings, path_set)| {
+                let (visited, edges) = resolver.walk(unit, settings, path_set);
+                for f in visited {
+                    visits[f] += 1;
+                }
+                for (edge, include) in edges {
+                    let entry = edge_counts.entry(edge).or_insert((0u32, include));
+                    entry.0 += 1;
+                    entry.1 = entry.1.min(include);
+                }
+                (visits, edge_counts)
+            },
+        )
+        .reduce(
+            || (vec![0u32; files.len()], HashMap::new()),
+            |(mut visits, mut edge_counts), (other_visits, other_edge_counts)| {
+                for (v, o) in visits.iter_mut().zip(other_visits) {
+                    *v += o;
+                }
+                for (edge, (count, include)) in other_edge_counts {
+                    let entry = edge_counts.entry(edge).or_insert((0, include));
+                    entry.0 += count;
+                    entry.1 = entry.1.min(include);
+                }
+                (visits, edge_counts)
+            },
+        );
+
+    // Second pass: for edges that only some of the translation units have, find out which.
+    let partial: HashSet<(FileRef, FileRef)> = edge_counts
         .iter()
-        .enumerate()
-        .par_bridge()
-        .for_each(|(i_file, file)| {
-            let file_path = root.join(&to_internal_path(&file.path));
-            let file_path_str = file_path.to_str().unwrap();
-            let include_paths: Vec<PathBuf> = match compile_commands.get(file_path_str) {
-                Some(paths) => paths.iter().map(PathBuf::from).collect(),
-                None => return,
+        .filter(|&(&(from, _), &(count, _))| count < visits[from])
+        .map(|(&edge, _)| edge)
+        .collect();
+    let mut edge_units: HashMap<(FileRef, FileRef), Vec<FileRef>> = HashMap::new();
+    if !partial.is_empty() {
+        let unit_edges: Vec<(FileRef, Vec<(FileRef, FileRef)>)> = units
+            .par_iter()
+            .map(|&(unit, settings, path_set)| {
+                let (_, edges) = resolver.walk(unit, settings, path_set);
+                let edges = edges.into_keys().filter(|e| partial.contains(e)).collect();
+                (unit, edges)
+            })
+            .collect();
+        for (unit, edges) in unit_edges {
+            for edge in edges {
+                let edge_units = edge_units.entry(edge).or_default();
+                // the settings of a file are consecutive units
+                if edge_units.last() != Some(&unit) {
+                    edge_units.push(unit);
+                }
+            }
+        }
+    }
+
+    Ok(file_links_from_edges(
+        files.len(),
+        edge_counts.into_iter().map(|(edge, (_, include))| {
+            let origin = LinkOrigin {
+                translation_units: edge_units.remove(&edge).unwrap_or_default(),
+                include: Some(include),
             };
-            fill_file_links(
-                &files,
-                &file_links,
-                &path_to_id,
-                &include_paths,
-                i_file,
-                &file.include_paths,
-                &options,
-            );
-        });
-    let file_links = std::mem::take(&mut (*file_links.lock().unwrap()));
-    file_links
+            (edge.0, edge.1, origin)
+        }),
+    ))
 }
 
-fn fill_file_links(
-    files: &[File],
-    file_links: &std::sync::Mutex<Vec<FileLinks>>,
-    path_to_id: &HashMap<String, FileRef>,
-    include_paths: &[PathBuf],
-    i_file: FileRef,
-    included_files: &[String],
-    options: &Opt,
-) {
-    for included_file in included_files {
-        let parent_dir = PathBuf::from(included_file);
-        let parent_dir = parent_dir.parent().unwrap();
-        let mut found_include = false;
-        for include_path in
-            std::iter::once(parent_dir).chain(include_paths.iter().map(PathBuf::as_path))
+type ResolveKey = (FileRef, usize, PathSetRef, Option<String>);
+
+/// Where an include leads in a translation unit.
+#[derive(Clone, Copy, Debug)]
+enum Resolved {
+    File(FileRef),
+    /// a file outside of the tree, like a system header
+    Outside,
+    /// no file at all
+    Missing,
+}
+
+impl Resolved {
+    /// Whether not walking the include may hide definitions that conditions of the tree test.
+    /// Headers outside of the tree, found or not, are assumed to leave the macros of the tree
+    /// alone, or else every `#ifdef` after `#include <stdio.h>` would be undecided. A quoted
+    /// include that leads nowhere is more likely a header of the project, like a generated
+    /// configuration.
+    fn hides_definitions(self, kind: IncludeKind) -> bool {
+        match self {
+            Resolved::File(_) | Resolved::Outside => false,
+            Resolved::Missing => kind != IncludeKind::Angle,
+        }
+    }
+}
+
+/// Resolves the includes of translation units.
+///
+/// Where an include leads only depends on the including file and the include paths, so the
+/// result is cached for each (file, include, include path set). Computed includes also depend on
+/// the macros, so their expansion is part of the key.
+struct IncludeResolver<'a> {
+    files: &'a [File],
+    root: PathBuf,
+    path_to_id: HashMap<String, FileRef>,
+    path_sets: Vec<SearchPaths>,
+    cache: RwLock<HashMap<ResolveKey, Resolved>>,
+    diagnostics: &'a Diagnostics,
+}
+
+impl IncludeResolver<'_> {
+    /// Returns the files visited by a translation unit and the edges between them, each with the
+    /// first include it comes from. Every file is entered once per translation unit, as if it
+    /// had include guards.
+    fn walk(
+        &self,
+        unit: FileRef,
+        settings: &CompileSettings,
+        path_set: PathSetRef,
+    ) -> (HashSet<FileRef>, HashMap<(FileRef, FileRef), usize>) {
+        let mut macros = settings.macros.clone();
+        let mut visited = HashSet::new();
+        let mut edges = HashMap::new();
+        visited.insert(unit);
+        self.walk_file(unit, path_set, &mut macros, &mut visited, &mut edges);
+        (visited, edges)
+    }
+
+    fn walk_file(
+        &self,
+        file: FileRef,
+        path_set: PathSetRef,
+        macros: &mut Macros,
+        visited: &mut HashSet<FileRef>,
+        edges: &mut HashMap<(FileRef, FileRef), usize>,
+    ) {
+        preprocessor::walk(
+            &self.files[file].directives,
+            macros,
+            &mut |include, macros| match self.resolve(file, include, path_set, macros) {
+                Resolved::File(included_file) => {
+                    let first = edges.entry((file, included_file)).or_insert(include);
+                    *first = (*first).min(include);
+                    if visited.insert(included_file) {
+                        self.walk_file(included_file, path_set, macros, visited, edges);
+                    }
+                }
+                resolved => {
+                    let kind = self.files[file].includes[include].kind;
+                    if resolved.hides_definitions(kind) {
+                        macros.set_incomplete();
+                    }
+                }
+            },
+        );
+    }
+
+    fn resolve(
+        &self,
+        file: FileRef,
+        include: usize,
+        path_set: PathSetRef,
+        macros: &Macros,
+    ) -> Resolved {
+        let spelling = &self.files[file].includes[include].spelling;
+        let expansion = if self.files[file].includes[include].is_computed() {
+            preprocessor::expand_include(spelling, macros).map(str::to_string)
+        } else {
+            None
+        };
+        let key = (file, include, path_set, expansion);
+        if let Some(&result) = self.cache.read().unwrap().get(&key) {
+            return result;
+        }
+        let result = self.resolve_uncached(file, include, path_set, key.3.as_deref());
+        self.cache.write().unwrap().insert(key, result);
+        result
+    }
+
+    fn resolve_uncached(
+        &self,
+        file: FileRef,
+        include: usize,
+        path_set: PathSetRef,
+        expansion: Option<&str>,
+    ) -> Resolved {
+        let file = &self.files[file];
+        let include = &file.includes[include];
+        let (included_file, kind) = if include.is_computed() {
+            match expansion.and_then(scanner::include_argument) {
+                Some(argument) => argument,
+                None => {
+                    self.diagnostics.report(
+                        Diagnostic::new(
+                            Category::MalformedInclude,
+                            format!(
+                                "computed include does not expand to a file name: {}",
+                                include.spelling
+                            ),
+                        )
+                        .file(&file.path)
+                        .line(include.line),
+                    );
+                    return Resolved::Missing;
+                }
+            }
+        } else {
+            (include.path.as_str(), include.kind)
+        };
+        let included_file = &included_file.replace('\\', "/");
+        let file_path = self.root.join(to_internal_path(&file.path));
+        let search_paths = &self.path_sets[path_set];
+        // #include_next continues after the search path the including file was found in
+        let next = match include.directive {
+            IncludeDirective::IncludeNext => search_paths
+                .paths
+                .iter()
+                .position(|p| file_path.starts_with(p))
+                .map(|i| i + 1),
+            _ => None,
+        };
+        let (parent_dir, paths) = match (next, kind) {
+            (Some(next), _) => (None, &search_paths.paths[next..]),
+            (None, IncludeKind::Quoted) => (file_path.parent(), &search_paths.paths[..]),
+            (None, _) => (None, &search_paths.paths[search_paths.angle_start..]),
+        };
+        // the first path that has the file wins, as in the compiler, even if it is outside of the
+        // tree and a later one is not
+        for include_path in parent_dir
+            .into_iter()
+            .chain(paths.iter().map(PathBuf::as_path))
         {
             let joined = include_path.join(to_internal_path(included_file));
             if !joined.exists() {
                 continue;
             }
-            //println!("{:?} <--> {:?}", included_file, joined);
-            let joined = joined.to_str().unwrap().to_lowercase();
-            let included_file_id = match path_to_id.get(&joined) {
-                Some(file_id) => *file_id,
-                None => {
-                    println!(
-                        "in file {}: include file outside build: {}",
-                        files[i_file].path, joined
-                    );
-                    continue;
-                }
-            };
-
-            let needs_recurse = {
-                let mut file_links = file_links.lock().unwrap();
-                file_links[i_file].outgoing_links.push(included_file_id);
-                file_links[included_file_id].incoming_links.push(i_file);
+            let joined = normalize(&joined).to_string_lossy().to_lowercase();
+            if let Some(&file_id) = self.path_to_id.get(&joined) {
+                return Resolved::File(file_id);
+            }
+            self.diagnostics.report(
+                Diagnostic::new(
+                    Category::OutsideInclude,
+                    format!("include file outside build: {}", joined),
+                )
+                .file(&file.path)
+                .line(include.line),
+            );
+            return Resolved::Outside;
+        }
+        self.diagnostics
+            .report(unresolved_include(file, include, included_file));
+        Resolved::Missing
+    }
+}
 
-                // This condition is not entirely correct: we might miss ODR violations
-                file_links[included_file_id].outgoing_links.is_empty()
-            };
+/// Collects the modules declared or imported by the files, sorted by name, and links each module
+/// to the modules its units import.
+fn generate_modules(files: &[File]) -> (Vec<Module>, Vec<ModuleLinks>) {
+    let mut names: Vec<&str> = files
+        .iter()
+        .flat_map(|f| {
+            f.module
+                .iter()
+                .map(|m| m.name.as_str())
+                .chain(f.imports.iter().map(|i| i.name.as_str()))
+        })
+        .collect();
+    names.sort_unstable();
+    names.dedup();
+    let name_to_ref: HashMap<&str, ModuleRef> =
+        names.iter().enumerate().map(|(i, &n)| (n, i)).collect();
 
-            found_include = true;
-
-            if needs_recurse {
-                fill_file_links(
-                    &files,
-                    &file_links,
-                    &path_to_id,
-                    &include_paths,
-                    included_file_id,
-                    &files[included_file_id].include_paths,
-                    &options,
-                );
+    let mut modules: Vec<Module> = names
+        .iter()
+        .map(|&name| Module {
+            name: name.to_string(),
+            units: vec![],
+        })
+        .collect();
+    let mut edges: Vec<(ModuleRef, ModuleRef)> = vec![];
+    for (i_file, file) in files.iter().enumerate() {
+        if let Some(module) = &file.module {
+            let m = name_to_ref[module.name.as_str()];
+            // interface units first
+            if module.exported {
+                modules[m].units.insert(0, i_file);
+            } else {
+                modules[m].units.push(i_file);
+            }
+            for import in &file.imports {
+                let to = name_to_ref[import.name.as_str()];
+                if to != m {
+                    edges.push((m, to));
+                }
             }
-            break;
-        }
-        if !found_include && options.warn_missing {
-            println!(
-                "include not found in {}: {}",
-                files[i_file].path, included_file
-            );
         }
     }
+    edges.sort_unstable();
+    edges.dedup();
+
+    let mut module_links = vec![ModuleLinks::default(); modules.len()];
+    for (from, to) in edges {
+        module_links[from].outgoing_links.push(to);
+        module_links[to].incoming_links.push(from);
+    }
+    (modules, module_links)
 }
 
 fn generate_is_public(file_links: &[FileLinks], file_components: &[ComponentRef]) -> Vec<bool> {
@@ -398,49 +1634,312 @@ fn generate_is_public(file_links: &[FileLinks], file_components: &[ComponentRef]
     is_public
 }
 
-fn load_compile_commands(path: &str) -> std::io::Result<HashMap<String, Vec<String>>> {
-    let f = std::fs::File::open(path)?;
-    let commands: Vec<CompileCommand> = serde_json::from_reader(std::io::BufReader::new(f))?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::Directive;
+    use crate::test_tree::TestTree;
 
-    println!("loading commands...");
-    std::io::stdout().flush().unwrap();
+    #[test]
+    fn join_relative_includes() {
+        // including file, include, joined path
+        let cases: &[(&str, &str, Option<&str>)] = &[
+            ("a/b/c.cpp", "../common/util.h", Some("a/common/util.h")),
+            ("a/b/c.cpp", "./d.h", Some("a/b/d.h")),
+            ("a/b/c.cpp", "../../d.h", Some("d.h")),
+            ("a/b/c.cpp", "../../../d.h", None),
+            ("a/b/c.cpp", "x/../d.h", Some("a/b/d.h")),
+            ("a/b/c.cpp", "..//d.h", Some("a/d.h")),
+            ("c.cpp", "d.h", Some("d.h")),
+        ];
+        for &(file, include, expected) in cases {
+            assert_eq!(
+                join_include(file, include).as_deref(),
+                expected,
+                "{} {}",
+                file,
+                include
+            );
+        }
+        assert!(is_relative_include("../a.h"));
+        assert!(is_relative_include("a/./b.h"));
+        assert!(!is_relative_include("a/b.h"));
+        assert!(!is_relative_include("a..b/c.h"));
+    }
 
-    let include_paths: HashMap<String, Vec<String>> = commands
-        .into_par_iter()
-        .map(|c| {
-            let file_name = match PathBuf::from(&c.file).canonicalize() {
-                Ok(path) => path,
-                Err(e) => {
-                    println!("file: {:?}\nerror: {:?}", c.file, e);
-                    return (c.file, vec![]);
-                }
-            };
-            let file_name = file_name.to_str().unwrap();
-            let mut include_paths = vec![];
-            let mut last_token = "";
-            for token in c.command.split(' ') {
-                if token.starts_with("-I") {
-                    // TODO: escaped paths? non-absolute paths?
-                    let path = PathBuf::from(&token[2..]);
-                    if let Ok(path) = path.canonicalize() {
-                        include_paths.push(path.to_str().unwrap().to_string());
-                    }
-                } else if last_token == "-isystem" {
-                    if let Ok(path) = PathBuf::from(token).canonicalize() {
-                        include_paths.push(path.to_str().unwrap().to_string());
-                    }
+    #[test]
+    fn walk_after_unwalked_include() {
+        // #include ..., then #ifdef USE_X #include "x.h" #else #include "y.h" #endif, without
+        // USE_X defined
+        let directives = [
+            Directive::Include(0),
+            Directive::Ifdef("USE_X".to_string()),
+            Directive::Include(1),
+            Directive::Else,
+            Directive::Include(2),
+            Directive::Endif,
+        ];
+        let cases: &[(IncludeKind, Resolved, &[usize])] = &[
+            // the first include, where it leads, the includes walked
+            (IncludeKind::Angle, Resolved::File(0), &[0, 2]),
+            (IncludeKind::Angle, Resolved::Outside, &[0, 2]),
+            // #include <stdio.h> without the system include paths
+            (IncludeKind::Angle, Resolved::Missing, &[0, 2]),
+            (IncludeKind::Quoted, Resolved::Outside, &[0, 2]),
+            // #include "config.h" that the build has not generated
+            (IncludeKind::Quoted, Resolved::Missing, &[0, 1, 2]),
+            (IncludeKind::Computed, Resolved::Missing, &[0, 1, 2]),
+        ];
+        for &(kind, resolved, expected) in cases {
+            let mut includes = vec![];
+            preprocessor::walk(&directives, &mut Macros::new(), &mut |include, macros| {
+                if include == 0 && resolved.hides_definitions(kind) {
+                    macros.set_incomplete();
                 }
-                last_token = token;
+                includes.push(include);
+            });
+            assert_eq!(includes, expected, "{:?} {:?}", kind, resolved);
+        }
+    }
+
+    #[test]
+    fn report_undetected_encodings() {
+        let tree = TestTree::new(
+            "encodings",
+            &[
+                ("a/CMakeLists.txt", b""),
+                ("a/utf8.cpp", "// \u{e9}\n#include \"a.h\"".as_bytes()),
+                (
+                    "a/utf16.cpp",
+                    b"\xff\xfe#\0i\0n\0c\0l\0u\0d\0e\0 \0\"\0a\0.\0h\0\"\0",
+                ),
+                ("a/latin1.cpp", b"// \xe9\n#include \"a.h\""),
+                ("a/a.h", b""),
+            ],
+        );
+        let graph = LoadOptions::new(tree.root()).load().unwrap();
+        let mut encodings: Vec<(&str, Encoding)> = graph
+            .files
+            .iter()
+            .map(|f| (f.path.as_str(), f.encoding))
+            .collect();
+        encodings.sort_unstable_by_key(|&(path, _)| path);
+        assert_eq!(
+            encodings,
+            [
+                ("a/a.h", Encoding::Utf8),
+                ("a/latin1.cpp", Encoding::Latin1),
+                ("a/utf16.cpp", Encoding::Utf16Le),
+                ("a/utf8.cpp", Encoding::Utf8),
+            ]
+        );
+        let reported: Vec<&str> = graph
+            .diagnostics
+            .iter()
+            .filter(|d| d.category == Category::Encoding)
+            .filter_map(|d| d.file.as_deref())
+            .collect();
+        assert_eq!(reported, ["a/latin1.cpp"]);
+        // the includes are found whatever the encoding
+        let a_h = graph.file_name_to_ref("a/a.h").unwrap();
+        assert_eq!(graph.file_links[a_h].incoming_links.len(), 3);
+    }
+
+    #[test]
+    fn name_components_distinctly() {
+        const APP: &str = "add_executable(app main.cpp)";
+        const CORE: &str = "add_library(core core.cpp core.h)";
+        const MANIFEST: &str = r#"{"components": [{"name": "app", "files": ["lib/app/**"]}]}"#;
+        // files with their contents, components with their files
+        type Case = (&'static [(&'static str, &'static str)], Components);
+        type Components = &'static [(&'static str, &'static [&'static str])];
+        let cases: &[Case] = &[
+            (
+                // the target takes all files of its directory, which is dropped
+                &[
+                    ("app/CMakeLists.txt", APP),
+                    ("app/main.cpp", "#include <core.h>"),
+                    ("core/CMakeLists.txt", CORE),
+                    ("core/core.cpp", "#include \"core.h\""),
+                    ("core/core.h", ""),
+                ],
+                &[
+                    (".", &[]),
+                    ("app", &["app/main.cpp"]),
+                    ("core", &["core/core.cpp", "core/core.h"]),
+                ],
+            ),
+            (
+                // the directory keeps a file the target does not list
+                &[
+                    ("core/CMakeLists.txt", CORE),
+                    ("core/core.cpp", ""),
+                    ("core/core.h", ""),
+                    ("core/extra.h", ""),
+                ],
+                &[
+                    (".", &[]),
+                    ("core", &["core/extra.h"]),
+                    ("core:core", &["core/core.cpp", "core/core.h"]),
+                ],
+            ),
+            (
+                // a manifest entry named like a directory of another path
+                &[
+                    ("app/CMakeLists.txt", ""),
+                    ("app/main.cpp", ""),
+                    ("lib/app/app.h", ""),
+                    ("components.json", MANIFEST),
+                ],
+                &[
+                    (".", &[]),
+                    ("app", &["app/main.cpp"]),
+                    ("lib/app:app", &["lib/app/app.h"]),
+                ],
+            ),
+        ];
+        for &(files, expected) in cases {
+            let files: Vec<(&str, &[u8])> = files.iter().map(|&(p, c)| (p, c.as_bytes())).collect();
+            let tree = TestTree::new("names", &files);
+            let mut options = LoadOptions::new(tree.root()).cmake_targets(true);
+            let manifest = tree.path("components.json");
+            if manifest.exists() {
+                options = options.component_manifest(manifest.to_str().unwrap());
             }
-            (file_name.to_string(), include_paths)
-        })
-        .collect();
+            let graph = options.load().unwrap();
+            let mut components: Vec<(&str, Vec<&str>)> = (0..graph.components.len())
+                .map(|c| {
+                    let mut files: Vec<&str> = graph.component_files[c]
+                        .iter()
+                        .map(|&f| graph.files[f].path.as_str())
+                        .collect();
+                    files.sort_unstable();
+                    (graph.components[c].nice_name(), files)
+                })
+                .collect();
+            components.sort_unstable();
+            let expected: Vec<(&str, Vec<&str>)> = expected
+                .iter()
+                .map(|&(c, files)| (c, files.to_vec()))
+                .collect();
+            assert_eq!(components, expected, "{:?}", files);
+        }
+    }
 
-    Ok(include_paths)
-}
+    #[test]
+    fn assign_files_by_manifest() {
+        let manifest = br#"{"components": [
+            {"name": "public", "files": ["core/core.h", "lib/*.h"]},
+            {"name": "other", "files": ["lib/api.h"]},
+            {"name": "empty", "files": []}
+        ]}"#;
+        let tree = TestTree::new(
+            "manifest",
+            &[
+                ("core/CMakeLists.txt", b"add_library(core core.cpp core.h)"),
+                ("core/core.cpp", b"#include \"core.h\""),
+                ("core/core.h", b""),
+                ("core/util.h", b""),
+                ("lib/api.h", b""),
+                ("lib/detail/impl.h", b""),
+                ("components.json", manifest),
+            ],
+        );
+        let graph = LoadOptions::new(tree.root())
+            .cmake_targets(true)
+            .component_manifest(tree.path("components.json").to_str().unwrap())
+            .load()
+            .unwrap();
+        let component = |path: &str| {
+            let f = graph.file_name_to_ref(path).unwrap();
+            graph.components[graph.file_components[f]].nice_name()
+        };
+        let cases = [
+            // over the target that lists it
+            ("core/core.h", "public"),
+            ("core/core.cpp", "core:core"),
+            // the directory keeps what neither claims
+            ("core/util.h", "core"),
+            // of overlapping entries, the first
+            ("lib/api.h", "public"),
+            ("lib/detail/impl.h", "."),
+        ];
+        for (path, expected) in cases {
+            assert_eq!(component(path), expected, "{}", path);
+        }
+        let empty = graph.component_name_to_ref("empty").unwrap();
+        assert!(graph.component_files[empty].is_empty());
+        let conflicts: Vec<(Option<&str>, &str)> = graph
+            .diagnostics
+            .iter()
+            .filter(|d| d.category == Category::ManifestConflict)
+            .map(|d| (d.file.as_deref(), d.message.as_str()))
+            .collect();
+        assert_eq!(
+            conflicts,
+            [(
+                Some("lib/api.h"),
+                "claimed by several components: public, other"
+            )]
+        );
+    }
 
-#[derive(serde::Deserialize)]
-struct CompileCommand {
-    command: String,
-    file: String,
+    #[test]
+    fn reuse_unaffected_resolutions() {
+        let tree = TestTree::new(
+            "resolutions",
+            &[
+                ("main.cpp", b"#include \"a.h\"\n#include \"b.h\""),
+                ("x/a.h", b""),
+                ("y/a.h", b""),
+                ("b.h", b""),
+            ],
+        );
+        let graph = LoadOptions::new(tree.root()).load().unwrap();
+        let main = graph.file_name_to_ref("main.cpp").unwrap();
+        let y = graph.file_name_to_ref("y/a.h").unwrap();
+        let resolved = |path: &str| vec![path.to_string()];
+        // the cached resolution of main.cpp, which a fresh one would tell apart by y/a.h
+        let earlier = vec![resolved("x/a.h"), resolved("b.h")];
+        let both = vec![
+            vec!["x/a.h".to_string(), "y/a.h".to_string()],
+            resolved("b.h"),
+        ];
+        let mut unrelated = graph.files[y].clone();
+        unrelated.path = "z/c.h".to_string();
+        let mut files = graph.files.clone();
+        files.push(unrelated);
+        let mut stamps = graph.stamps.clone();
+        stamps.push(stamps[main]);
+        // the files the earlier run did not have, what "a.h" resolves to now
+        let cases: &[(&[&str], &Resolution)] = &[
+            (&["z/c.h"], &earlier),
+            (&["y/a.h", "z/c.h"], &both),
+            (&[], &earlier),
+        ];
+        for &(left_out, expected) in cases {
+            let kept: Vec<usize> = (0..files.len())
+                .filter(|&i| !left_out.contains(&files[i].path.as_str()))
+                .collect();
+            let files: Vec<File> = kept.iter().map(|&i| files[i].clone()).collect();
+            let stamps: Vec<_> = kept.iter().map(|&i| stamps[i]).collect();
+            let resolutions: Vec<_> = kept
+                .iter()
+                .map(|&i| (i == main).then(|| earlier.clone()))
+                .collect();
+            let cache = ScanCache::from_files(&files, &stamps, &resolutions);
+            for (file, stamp) in graph.files.iter().zip(&graph.stamps) {
+                cache.take(&file.path, stamp.unwrap());
+            }
+            let (_, resolutions) = generate_file_links(
+                &graph.files,
+                &graph.file_components,
+                &cache,
+                &Diagnostics::default(),
+            );
+            let mut resolution = resolutions[main].clone().unwrap();
+            resolution.iter_mut().for_each(|r| r.sort());
+            assert_eq!(&resolution, expected, "{:?}", left_out);
+        }
+    }
 }
@@ -1,57 +1,44 @@
-use crate::graph::{ComponentRef, Edge, Graph};
-use std::collections::HashMap;
-use std::io::Write;
+use cpdep::error::{Context, Result};
+use cpdep::graph::{ComponentRef, Edge, Graph};
 use std::path::{Path, PathBuf};
 
-pub fn export(graph: &Graph, path: &str) -> std::io::Result<()> {
+pub fn export(graph: &Graph, path: &str) -> Result<()> {
     let path = PathBuf::from(path);
-    std::fs::create_dir_all(&path).unwrap();
+    std::fs::create_dir_all(&path).context("create directory", &path)?;
 
-    {
-        let html = Index { graph }.to_string();
-        let mut f = std::fs::File::create(&path.join("index.html")).unwrap();
-        f.write_all(html.as_bytes())?;
-    }
+    let html = Index { graph }.to_string();
+    let index = path.join("index.html");
+    std::fs::write(&index, html).context("write", &index)?;
 
     for c_ref in 0..graph.components.len() {
-        export_component(&graph, c_ref, &path)?;
+        export_component(graph, c_ref, &path)?;
     }
     Ok(())
 }
 
-fn export_component(graph: &Graph, c: ComponentRef, root: &Path) -> std::io::Result<()> {
+fn export_component(graph: &Graph, c: ComponentRef, root: &Path) -> Result<()> {
     let name = graph.components[c].nice_name();
-    let path = root.join(&format!("{}.html", name.replace("/", "__")));
+    let path = root.join(format!("{}.html", name.replace("/", "__")));
 
-    let (dep_in, dep_out) = graph.linked_components(c, false);
+    let links = graph.component_links(c, false);
 
     let html = Page {
-        graph: &graph,
-        name: &name,
-        dep_in: &dep_in,
-        dep_out: &dep_out,
+        graph,
+        name,
+        dep_in: &links.incoming,
+        dep_out: &links.outgoing,
     }
     .to_string();
 
-    let mut f = std::fs::File::create(&path).unwrap();
-    f.write_all(html.as_bytes())?;
-
-    Ok(())
-}
-
-fn sorted_dep_keys(graph: &Graph, deps: &HashMap<ComponentRef, Vec<Edge>>) -> Vec<ComponentRef> {
-    let mut sorted_keys: Vec<ComponentRef> = deps.keys().cloned().collect();
-    let sort_fn = |a: &ComponentRef, b: &ComponentRef| {
-        graph.components[*a].path.cmp(&graph.components[*b].path)
-    };
-    sorted_keys.sort_by(sort_fn);
-    sorted_keys
+    std::fs::write(&path, html).context("write", &path)
 }
 
 fn sorted_components(graph: &Graph) -> Vec<ComponentRef> {
     let mut sorted_keys: Vec<ComponentRef> = (0..graph.components.len()).collect();
     let sort_fn = |a: &ComponentRef, b: &ComponentRef| {
-        graph.components[*a].path.cmp(&graph.components[*b].path)
+        graph.components[*a]
+            .sort_key()
+            .cmp(&graph.components[*b].sort_key())
     };
     sorted_keys.sort_by(sort_fn);
     sorted_keys
@@ -65,7 +52,7 @@ markup::define! {
             body {
                 h1 { "ModuleWorks C++ Dependencies" }
                 ul {
-                    @for c in sorted_components(&graph).into_iter().map(|c_ref| &graph.components[c_ref]) {
+                    @for c in sorted_components(graph).into_iter().map(|c_ref| &graph.components[c_ref]) {
                         li {
                             {c.nice_name()}
                             " "
@@ -82,8 +69,8 @@ markup::define! {
     Page<'a>(
         graph: &'a Graph,
         name: &'a str,
-        dep_in: &'a HashMap<ComponentRef, Vec<Edge>>,
-        dep_out: &'a HashMap<ComponentRef, Vec<Edge>>
+        dep_in: &'a [(ComponentRef, Vec<Edge>)],
+        dep_out: &'a [(ComponentRef, Vec<Edge>)]
     )  {
         {markup::doctype()}
         html {
@@ -128,25 +115,25 @@ markup::define! {
 
     Deps<'a>(
         graph: &'a Graph,
-        deps: &'a HashMap<ComponentRef, Vec<Edge>>
+        deps: &'a [(ComponentRef, Vec<Edge>)]
     ) {
-        @for c_ref in sorted_dep_keys(&graph, &deps) {
+        @for (c_ref, edges) in deps.iter() {
             details {
                 summary {
-                    {graph.components[c_ref].nice_name()}
+                    {graph.components[*c_ref].nice_name()}
                     span[class="dep-count"] {
                         " ("
-                        {deps[&c_ref].len()}
+                        {edges.len()}
                         ") "
                     }
-                    a[href=format!("{}.html", graph.components[c_ref].nice_name().replace('/', "__"))] {
+                    a[href=format!("{}.html", graph.components[*c_ref].nice_name().replace('/', "__"))] {
                         "[go]"
                     }
                 }
                 ul {
-                    @for e in &deps[&c_ref] {
+                    @for e in edges {
                         li {
-                            {graph.files[e.from].path}
+                            {graph.edge_location(e)}
                             " → "
                             {graph.files[e.to].path}
                         }
@@ -0,0 +1,148 @@
+//! Reading of build logs with include trees: MSVC `/showIncludes` and Clang/GCC `-H` output.
+
+use crate::language::LanguageProfile;
+use std::path::PathBuf;
+
+/// The include tree of a translation unit, as printed by the compiler.
+pub struct IncludeTree {
+    /// the source file, if the log names it
+    pub source: Option<PathBuf>,
+    /// included files with their nesting depth, starting at 1, in the order they were printed
+    pub includes: Vec<(usize, PathBuf)>,
+}
+
+const MSVC_NOTE: &str = "Note: including file:";
+
+pub fn load(path: &str, profile: &LanguageProfile) -> std::io::Result<Vec<IncludeTree>> {
+    let bytes = std::fs::read(path)?;
+    Ok(parse(&String::from_utf8_lossy(&bytes), profile))
+}
+
+/// Splits a log into include trees. A new tree starts at every line that names a source file,
+/// like the file name MSVC prints before compiling it, or a compile command in a Ninja or Make log.
+fn parse(log: &str, profile: &LanguageProfile) -> Vec<IncludeTree> {
+    let mut trees: Vec<IncludeTree> = vec![];
+    for line in log.lines() {
+        let include = if let Some(idx) = line.find(MSVC_NOTE) {
+            // one space after the colon, and one more for each nesting level
+            let rest = &line[idx + MSVC_NOTE.len()..];
+            let path = rest.trim_start_matches(' ');
+            let depth = rest.len() - path.len();
+            Some((depth, path.trim_end()))
+        } else {
+            let path = line.trim_start_matches('.');
+            let depth = line.len() - path.len();
+            if depth > 0 && path.starts_with(' ') {
+                Some((depth, path.trim()))
+            } else {
+                None
+            }
+        };
+
+        match include {
+            Some((depth, path)) if depth > 0 && !path.is_empty() => {
+                if trees.is_empty() {
+                    trees.push(IncludeTree {
+                        source: None,
+                        includes: vec![],
+                    });
+                }
+                let tree = trees.last_mut().unwrap();
+                tree.includes.push((depth, PathBuf::from(path)));
+            }
+            _ => {
+                if let Some(source) = source_in_line(line, profile) {
+                    trees.push(IncludeTree {
+                        source: Some(PathBuf::from(source)),
+                        includes: vec![],
+                    });
+                }
+            }
+        }
+    }
+    trees.retain(|t| !t.includes.is_empty());
+    trees
+}
+
+/// Returns the last word of a line that looks like a source file.
+fn source_in_line<'a>(line: &'a str, profile: &LanguageProfile) -> Option<&'a str> {
+    // MSBuild prefixes lines with the project number, as in "1>main.cpp"
+    let line = match line.find('>') {
+        Some(idx) if line[..idx].bytes().all(|b| b.is_ascii_digit()) => &line[idx + 1..],
+        _ => line,
+    };
+    line.split_whitespace()
+        .map(|w| w.trim_matches('"'))
+        .rfind(|w| profile.is_translation_unit(w))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Trees = Vec<(Option<String>, Vec<(usize, String)>)>;
+
+    fn parsed(log: &str) -> Trees {
+        parse(log, &LanguageProfile::default())
+            .into_iter()
+            .map(|tree| {
+                let source = tree.source.map(|s| s.to_string_lossy().into_owned());
+                let includes = tree
+                    .includes
+                    .into_iter()
+                    .map(|(depth, path)| (depth, path.to_string_lossy().into_owned()))
+                    .collect();
+                (source, includes)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn parse_logs() {
+        let tree = |source: Option<&str>, includes: &[(usize, &str)]| {
+            (
+                source.map(str::to_string),
+                includes
+                    .iter()
+                    .map(|&(depth, path)| (depth, path.to_string()))
+                    .collect(),
+            )
+        };
+        let cases: Vec<(&str, Trees)> = vec![
+            // MSVC /showIncludes, with the file name printed before compiling it
+            (
+                "main.cpp\nNote: including file: C:\\src\\a.h\nNote: including file:  C:\\src\\b.h\n",
+                vec![tree(Some("main.cpp"), &[(1, "C:\\src\\a.h"), (2, "C:\\src\\b.h")])],
+            ),
+            // MSBuild project prefixes and trailing whitespace
+            (
+                "1>main.cpp\n1>Note: including file: a.h \r\n2>other.cpp\n2>Note: including file: b.h\n",
+                vec![
+                    tree(Some("main.cpp"), &[(1, "a.h")]),
+                    tree(Some("other.cpp"), &[(1, "b.h")]),
+                ],
+            ),
+            // -H output after the compile commands of a Ninja log
+            (
+                "[1/2] g++ -H -c ../src/a.cpp -o a.o\n. ../src/a.h\n.. /usr/include/stdio.h\n[2/2] g++ -c \"../src/b.cpp\"\n. ../src/b.h\n",
+                vec![
+                    tree(
+                        Some("../src/a.cpp"),
+                        &[(1, "../src/a.h"), (2, "/usr/include/stdio.h")],
+                    ),
+                    tree(Some("../src/b.cpp"), &[(1, "../src/b.h")]),
+                ],
+            ),
+            // includes before any source file, and sources without includes
+            (
+                ". a.h\nb.cpp\nc.cpp\n. c.h\n",
+                vec![tree(None, &[(1, "a.h")]), tree(Some("c.cpp"), &[(1, "c.h")])],
+            ),
+            // lines that only look like includes
+            ("...\n.hidden\nMultiple include guards may be useful for:\n", vec![]),
+        ];
+        for (log, expected) in cases {
+            assert_eq!(parsed(log), expected, "{:?}", log);
+        }
+    }
+}
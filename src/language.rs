@@ -0,0 +1,165 @@
+//! The language profile: which file suffixes are headers, sources and module interfaces.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileKind {
+    Header,
+    Source,
+    ModuleInterface,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LanguageProfile {
+    pub headers: Vec<String>,
+    pub sources: Vec<String>,
+    pub module_interfaces: Vec<String>,
+}
+
+const HEADERS: [&str; 10] = [
+    ".h", ".hh", ".hpp", ".hxx", ".h++", ".H", ".inl", ".ipp", ".tcc", ".cuh",
+];
+const SOURCES: [&str; 9] = [
+    ".c", ".cc", ".cpp", ".cxx", ".c++", ".C", ".cu", ".m", ".mm",
+];
+const MODULE_INTERFACES: [&str; 3] = [".ixx", ".cppm", ".mpp"];
+
+impl Default for LanguageProfile {
+    fn default() -> LanguageProfile {
+        let to_strings = |suffixes: &[&str]| suffixes.iter().map(|s| s.to_string()).collect();
+        LanguageProfile {
+            headers: to_strings(&HEADERS),
+            sources: to_strings(&SOURCES),
+            module_interfaces: to_strings(&MODULE_INTERFACES),
+        }
+    }
+}
+
+/// The contents of a profile file. Its suffixes are added to the default ones, after the
+/// `remove` ones are taken out of them.
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProfileFile {
+    headers: Vec<String>,
+    sources: Vec<String>,
+    module_interfaces: Vec<String>,
+    remove: Vec<String>,
+}
+
+impl LanguageProfile {
+    /// Reads a JSON file like `{"headers": [".imp"], "sources": [".pc"], "remove": [".m"]}`,
+    /// see `parse`.
+    pub fn load(path: &str) -> std::io::Result<LanguageProfile> {
+        Ok(LanguageProfile::parse(&std::fs::read(path)?)?)
+    }
+
+    /// Applies a profile file to the defaults: removes the suffixes of `remove` from all kinds,
+    /// then adds the suffixes of each kind. A suffix given for another kind than its default one
+    /// moves there.
+    fn parse(json: &[u8]) -> serde_json::Result<LanguageProfile> {
+        let file: ProfileFile = serde_json::from_slice(json)?;
+        let mut profile = LanguageProfile::default();
+        let added: Vec<&String> = file
+            .headers
+            .iter()
+            .chain(&file.sources)
+            .chain(&file.module_interfaces)
+            .collect();
+        for suffixes in [
+            &mut profile.headers,
+            &mut profile.sources,
+            &mut profile.module_interfaces,
+        ] {
+            suffixes.retain(|s| !file.remove.contains(s) && !added.contains(&s));
+        }
+        profile.headers.extend(file.headers);
+        profile.sources.extend(file.sources);
+        profile.module_interfaces.extend(file.module_interfaces);
+        Ok(profile)
+    }
+
+    pub fn file_kind(&self, path: &str) -> Option<FileKind> {
+        let matches = |suffixes: &[String]| suffixes.iter().any(|s| path.ends_with(s.as_str()));
+        if matches(&self.headers) {
+            Some(FileKind::Header)
+        } else if matches(&self.sources) {
+            Some(FileKind::Source)
+        } else if matches(&self.module_interfaces) {
+            Some(FileKind::ModuleInterface)
+        } else {
+            None
+        }
+    }
+
+    /// Whether a file is compiled on its own, as opposed to being included.
+    pub fn is_translation_unit(&self, path: &str) -> bool {
+        matches!(
+            self.file_kind(path),
+            Some(FileKind::Source) | Some(FileKind::ModuleInterface)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_files() {
+        let cases: &[(&str, &str, Option<FileKind>)] = &[
+            // profile file, path, kind
+            ("{}", "a/b.h", Some(FileKind::Header)),
+            ("{}", "a/b.cpp", Some(FileKind::Source)),
+            ("{}", "a/b.cppm", Some(FileKind::ModuleInterface)),
+            ("{}", "a/b.imp", None),
+            ("{}", "a/b.txt", None),
+            (
+                r#"{"headers": [".imp", ".impl"]}"#,
+                "a/b.imp",
+                Some(FileKind::Header),
+            ),
+            (
+                r#"{"headers": [".imp", ".impl"]}"#,
+                "a/b.h",
+                Some(FileKind::Header),
+            ),
+            (r#"{"sources": [".pc"]}"#, "a/b.pc", Some(FileKind::Source)),
+            (r#"{"headers": []}"#, "a/b.cpp", Some(FileKind::Source)),
+            (r#"{"remove": [".m", ".mm"]}"#, "a/b.m", None),
+            (
+                r#"{"remove": [".m", ".mm"]}"#,
+                "a/b.c",
+                Some(FileKind::Source),
+            ),
+            (r#"{"remove": [".h"]}"#, "a/b.h", None),
+            // a suffix moves to the kind it is given for
+            (r#"{"headers": [".cu"]}"#, "a/b.cu", Some(FileKind::Header)),
+            (
+                r#"{"sources": [".inl"]}"#,
+                "a/b.inl",
+                Some(FileKind::Source),
+            ),
+            (
+                r#"{"module_interfaces": [".cpp"]}"#,
+                "a/b.cpp",
+                Some(FileKind::ModuleInterface),
+            ),
+            // removing and adding again gives the added kind
+            (
+                r#"{"remove": [".h"], "sources": [".h"]}"#,
+                "a/b.h",
+                Some(FileKind::Source),
+            ),
+        ];
+        for &(json, path, kind) in cases {
+            let profile = LanguageProfile::parse(json.as_bytes()).unwrap();
+            assert_eq!(profile.file_kind(path), kind, "{} {}", json, path);
+        }
+        assert_eq!(
+            LanguageProfile::parse(b"{}").unwrap(),
+            LanguageProfile::default()
+        );
+        assert!(LanguageProfile::parse(br#"{"header": [".imp"]}"#).is_err());
+        assert!(LanguageProfile::parse(b"{\"headers\": [").is_err());
+    }
+}
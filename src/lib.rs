@@ -0,0 +1,28 @@
+//! Dependencies between the components of a C/C++ source tree, from the includes of its files.
+//!
+//! Load a `graph::Graph` with `graph::LoadOptions`, then query it with the methods in `query`.
+//! A `Watcher` tells when files change that the graph was loaded from, to reload it.
+
+mod cmake_file_api;
+mod cmake_targets;
+mod compile_commands;
+mod component_detector;
+mod component_manifest;
+mod depfile;
+pub mod diagnostics;
+mod encoding;
+pub mod error;
+mod file_collector;
+pub mod graph;
+mod include_log;
+mod language;
+mod ninja_deps;
+mod preprocessor;
+pub mod query;
+mod scan_cache;
+mod scanner;
+#[cfg(test)]
+mod test_tree;
+mod watcher;
+
+pub use watcher::{Change, Watcher};
@@ -1,29 +1,81 @@
+use structopt::clap::ArgGroup;
 use structopt::StructOpt;
 
+use cpdep::diagnostics::Category;
+use cpdep::error::{Error, Result};
+use cpdep::graph::{ComponentDetector, Graph, LinkSource, LoadOptions};
+use cpdep::{Change, Watcher};
+use std::process::ExitCode;
+
 mod cli;
-mod file_collector;
-mod graph;
 mod html;
 mod ui;
 
 #[derive(StructOpt)]
-pub struct Opt {
+#[structopt(group = ArgGroup::with_name("source").required(true))]
+#[structopt(after_help = "EXIT CODES:
+    1    a checked rule is violated, like components that link in cycles for scc
+    2    usage error, like a component name that does not exist
+    3    input error, like a file that cannot be read or parsed")]
+struct Opt {
     #[structopt(long, group = "source")]
     root: Option<String>,
 
     #[structopt(long, group = "source")]
     import: Option<String>,
 
-    #[structopt(long)]
+    #[structopt(long, group = "links")]
     compile_commands: Option<String>,
 
-    /// warn about missing includes
+    /// derive links from the .ninja_deps log in this build directory
+    #[structopt(long, group = "links")]
+    ninja_deps: Option<String>,
+
+    /// derive links from the .d depfiles in this build directory
+    #[structopt(long, group = "links")]
+    depfiles: Option<String>,
+
+    /// take links from a build log with MSVC /showIncludes or Clang -H output
+    #[structopt(long, group = "links")]
+    include_log: Option<String>,
+
+    /// build files that make a directory a component: cmake, bazel, meson, msbuild, make
+    #[structopt(long, default_value = "cmake", require_delimiter = true)]
+    component_detectors: Vec<ComponentDetector>,
+
+    /// assign files to the CMake targets that list them instead of to directories
+    #[structopt(long)]
+    cmake_targets: bool,
+
+    /// take targets, their sources and include paths from the CMake File API reply in this
+    /// build directory; the dependencies between targets are not read, links come from includes
+    #[structopt(long, group = "links", conflicts_with = "cmake-targets")]
+    cmake_reply: Option<String>,
+
+    /// a JSON file of named components and the glob patterns of their files, which take
+    /// precedence over directories and targets
+    #[structopt(long)]
+    component_manifest: Option<String>,
+
+    /// keep the scanned includes in this file and only rescan files that changed since
     #[structopt(long)]
-    warn_missing: bool,
+    scan_cache: Option<String>,
 
-    /// warn about malformed includes
+    /// a JSON file with additional "headers", "sources" and "module_interfaces" suffixes, and
+    /// default ones to "remove"
     #[structopt(long)]
-    warn_malformed: bool,
+    language_profile: Option<String>,
+
+    /// watch the root and reload the graph when files change: the UI refreshes, other commands
+    /// run again
+    #[structopt(long, requires = "root")]
+    watch: bool,
+
+    /// also print these categories of diagnostics while loading: missing-include,
+    /// malformed-include, computed-include, encoding, mismatch. outside-include, unknown-source,
+    /// unreadable-file, manifest-conflict and unresolved-target are always printed.
+    #[structopt(long, require_delimiter = true)]
+    warn: Vec<Category>,
 
     #[structopt(subcommand)]
     cmd: Cmd,
@@ -56,13 +108,17 @@ enum Cmd {
     File {
         file_name: String,
     },
+    /// list C++20 modules, or show the units and links of the given module
+    Module {
+        module_name: Option<String>,
+    },
     /// show terminal UI
     UI {},
     /// export as html to the given directory
-    HTML {
+    Html {
         directory: String,
     },
-    /// show all strongly connected components
+    /// show all strongly connected components, failing if there are any
     Scc {},
     /// list the shortest path from component A to B
     Shortest {
@@ -78,35 +134,180 @@ enum Cmd {
     Export {
         path: String,
     },
+    /// list the problems found while loading, grouped by category
+    Diagnostics {
+        /// only list these categories
+        #[structopt(long, require_delimiter = true)]
+        category: Vec<Category>,
+
+        /// text or json
+        #[structopt(long, default_value = "text")]
+        format: cli::Format,
+    },
 }
 
-fn main() -> Result<(), failure::Error> {
-    let options = Opt::from_args();
-    let graph = graph::load(&options);
+impl Opt {
+    fn load_options(&self, root: &str) -> LoadOptions {
+        let links = if let Some(path) = &self.compile_commands {
+            LinkSource::CompileCommands(path.clone())
+        } else if let Some(dir) = &self.cmake_reply {
+            LinkSource::CMakeReply(dir.clone())
+        } else if let Some(dir) = &self.ninja_deps {
+            LinkSource::NinjaDeps(dir.clone())
+        } else if let Some(dir) = &self.depfiles {
+            LinkSource::Depfiles(dir.clone())
+        } else if let Some(path) = &self.include_log {
+            LinkSource::IncludeLog(path.clone())
+        } else {
+            LinkSource::Includes
+        };
+        let mut options = LoadOptions::new(root)
+            .links(links)
+            .component_detectors(self.component_detectors.clone())
+            .cmake_targets(self.cmake_targets)
+            .progress(|message| eprintln!("{}", message));
+        if let Some(path) = &self.component_manifest {
+            options = options.component_manifest(path);
+        }
+        if let Some(path) = &self.language_profile {
+            options = options.language_profile(path);
+        }
+        if let Some(path) = &self.scan_cache {
+            options = options.scan_cache(path);
+        }
+        options
+    }
 
-    match options.cmd {
+    fn print_load_diagnostics(&self, graph: &Graph) {
+        // the diagnostics command lists them itself
+        if let Cmd::Diagnostics { .. } = self.cmd {
+            return;
+        }
+        cli::print_load_diagnostics(graph, &self.warn);
+    }
+}
+
+/// Exit codes, so that a CI job can tell a failed check from a broken setup.
+const EXIT_RULE_VIOLATION: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_INPUT: u8 = 3;
+
+/// Whether the rules that a command checks hold.
+#[derive(PartialEq)]
+enum Verdict {
+    Pass,
+    /// like `scc` finding components that link in cycles
+    Violation,
+}
+
+fn main() -> ExitCode {
+    let opt = match Opt::from_args_safe() {
+        Ok(opt) => opt,
+        // --help and --version are not errors
+        Err(e) if !e.use_stderr() => {
+            println!("{}", e.message);
+            return ExitCode::SUCCESS;
+        }
+        Err(e) => {
+            eprintln!("{}", e.message);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    match run_opt(&opt) {
+        Ok(Verdict::Pass) => ExitCode::SUCCESS,
+        Ok(Verdict::Violation) => ExitCode::from(EXIT_RULE_VIOLATION),
+        Err(e) => {
+            eprintln!("error: {}", e);
+            let code = if e.is_usage_error() {
+                EXIT_USAGE
+            } else {
+                EXIT_INPUT
+            };
+            ExitCode::from(code)
+        }
+    }
+}
+
+fn run_opt(opt: &Opt) -> Result<Verdict> {
+    let options = opt.root.as_ref().map(|root| opt.load_options(root));
+    let mut graph = match (&options, &opt.import) {
+        (Some(options), _) => options.load()?,
+        (None, Some(path)) => Graph::import(path)?,
+        (None, None) => return Err(Error::Usage("either --root or --import is required")),
+    };
+    opt.print_load_diagnostics(&graph);
+
+    let watch = options.as_ref().filter(|_| opt.watch);
+    if let Cmd::UI {} = opt.cmd {
+        ui::show_ui(&mut graph, watch)?;
+        return Ok(Verdict::Pass);
+    }
+    let verdict = run(&graph, &opt.cmd)?;
+    if let Some(options) = watch {
+        let mut watcher = Watcher::new(options, &graph.profile)?;
+        loop {
+            let reloaded = match watcher.wait()? {
+                Change::Files => graph.reload(options),
+                Change::Overflow => graph.reload_all(options),
+            };
+            // keep watching, the files may be fixed with the next change
+            let changed = match reloaded {
+                Ok(changed) => changed,
+                Err(e) => {
+                    eprintln!("error: {}", e);
+                    continue;
+                }
+            };
+            opt.print_load_diagnostics(&graph);
+            let names: Vec<&str> = changed
+                .iter()
+                .map(|&c| graph.components[c].nice_name())
+                .collect();
+            println!("--- changed components: {}", names.join(", "));
+            if let Err(e) = run(&graph, &opt.cmd) {
+                eprintln!("error: {}", e);
+            }
+        }
+    }
+
+    Ok(verdict)
+}
+
+fn run(graph: &Graph, cmd: &Cmd) -> Result<Verdict> {
+    match cmd {
         Cmd::Component {
             component_from,
             component_to,
             verbose,
             only_public,
-        } => cli::print_components(&graph, component_from, component_to, verbose, only_public),
-        Cmd::File { file_name } => cli::print_file_info(&graph, &file_name),
-        Cmd::Headers { component, verbose } => cli::print_headers(&graph, component, verbose),
-        Cmd::UI {} => ui::show_ui(&graph)?,
-        Cmd::HTML { directory } => html::export(&graph, &directory)?,
-        Cmd::Scc {} => cli::show_sccs(&graph),
+        } => cli::print_components(
+            graph,
+            component_from.clone(),
+            component_to.clone(),
+            *verbose,
+            *only_public,
+        )?,
+        Cmd::File { file_name } => cli::print_file_info(graph, file_name)?,
+        Cmd::Module { module_name } => cli::print_modules(graph, module_name.clone())?,
+        Cmd::Headers { component, verbose } => {
+            cli::print_headers(graph, component.clone(), *verbose)?
+        }
+        Cmd::UI {} => unreachable!("the UI is shown by main"),
+        Cmd::Html { directory } => html::export(graph, directory)?,
+        Cmd::Scc {} => {
+            if cli::show_sccs(graph) > 0 {
+                return Ok(Verdict::Violation);
+            }
+        }
         Cmd::Shortest {
             component_from,
             component_to,
             verbose,
             only_public,
-        } => cli::print_shortest(&graph, &component_from, &component_to, verbose, only_public),
-        Cmd::Export { path } => {
-            let encoded = bincode::serialize(&graph)?;
-            std::fs::write(path, &encoded)?;
-        }
+        } => cli::print_shortest(graph, component_from, component_to, *verbose, *only_public)?,
+        Cmd::Export { path } => graph.export(path)?,
+        Cmd::Diagnostics { category, format } => cli::print_diagnostics(graph, category, format)?,
     }
 
-    Ok(())
+    Ok(Verdict::Pass)
 }
@@ -0,0 +1,206 @@
+//! Reading of the `.ninja_deps` binary log, in which Ninja records discovered header dependencies.
+//!
+//! The log starts with a signature and a version, followed by records of two kinds. Path records
+//! assign consecutive ids to paths, deps records list the input ids of an output. A later deps
+//! record of the same output replaces the earlier one.
+
+use crate::depfile::Dependencies;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+
+const SIGNATURE: &[u8] = b"# ninjadeps\n";
+
+/// Reads `.ninja_deps` in the build directory `dir`. Paths are resolved against `dir`.
+///
+/// The first input of an output is taken as its source file. That holds for `deps = gcc`, where
+/// Ninja stores the depfile contents; for `deps = msvc` the source file is not recorded.
+pub fn load(dir: &str) -> std::io::Result<Vec<Dependencies>> {
+    let dir = Path::new(dir);
+    let dir = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    let bytes = std::fs::read(dir.join(".ninja_deps"))?;
+    parse(&bytes, &dir)
+}
+
+fn parse(bytes: &[u8], dir: &Path) -> std::io::Result<Vec<Dependencies>> {
+    let invalid = |message: &str| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+
+    let rest = bytes
+        .strip_prefix(SIGNATURE)
+        .ok_or_else(|| invalid("not a ninja deps log"))?;
+    let version = read_u32(rest, 0).ok_or_else(|| invalid("truncated ninja deps log"))?;
+    if version != 3 && version != 4 {
+        return Err(invalid(&format!(
+            "unsupported ninja deps log version {}",
+            version
+        )));
+    }
+    let mtime_size = if version == 4 { 8 } else { 4 };
+
+    let mut paths: Vec<&[u8]> = vec![];
+    let mut deps: HashMap<u32, Vec<u32>> = HashMap::new();
+    let mut pos = 4;
+    while let Some(header) = read_u32(rest, pos) {
+        let is_deps = header & 0x8000_0000 != 0;
+        let size = (header & 0x7fff_ffff) as usize;
+        let record = match rest.get(pos + 4..pos + 4 + size) {
+            Some(record) => record,
+            // a truncated last record is left by an interrupted build
+            None => break,
+        };
+        pos += 4 + size;
+
+        if is_deps {
+            if size < 4 + mtime_size || size % 4 != 0 {
+                return Err(invalid("malformed deps record"));
+            }
+            let output = read_u32(record, 0).unwrap();
+            let inputs = (4 + mtime_size..size)
+                .step_by(4)
+                .map(|i| read_u32(record, i).unwrap())
+                .collect();
+            deps.insert(output, inputs);
+        } else {
+            // the path is padded with NULs and followed by a checksum
+            let path = &record[..size.saturating_sub(4)];
+            let len = path.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+            paths.push(&path[..len]);
+        }
+    }
+
+    let to_path = |id: u32| -> Option<PathBuf> {
+        let path = paths.get(id as usize)?;
+        Some(dir.join(String::from_utf8_lossy(path).as_ref()))
+    };
+    let mut outputs: Vec<u32> = deps.keys().copied().collect();
+    outputs.sort_unstable();
+    Ok(outputs
+        .into_iter()
+        .filter_map(|output| {
+            let mut inputs = deps[&output].iter().filter_map(|&id| to_path(id));
+            let source = inputs.next()?;
+            Some(Dependencies {
+                source,
+                headers: inputs.collect(),
+            })
+        })
+        .collect())
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    let b = bytes.get(pos..pos + 4)?;
+    Some(u32::from_le_bytes(b.try_into().unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a log with the given records, each a deps flag and its contents.
+    fn log(version: u32, records: &[(bool, Vec<u8>)]) -> Vec<u8> {
+        let mut bytes = SIGNATURE.to_vec();
+        bytes.extend(version.to_le_bytes());
+        for (is_deps, record) in records {
+            let header = record.len() as u32 | if *is_deps { 0x8000_0000 } else { 0 };
+            bytes.extend(header.to_le_bytes());
+            bytes.extend(record);
+        }
+        bytes
+    }
+
+    fn path(path: &str) -> (bool, Vec<u8>) {
+        let mut record = path.as_bytes().to_vec();
+        while record.len() % 4 != 0 {
+            record.push(0);
+        }
+        // followed by a checksum of the id, which the reader doesn't check
+        record.extend([0xff; 4]);
+        (false, record)
+    }
+
+    fn deps(mtime_size: usize, output: u32, inputs: &[u32]) -> (bool, Vec<u8>) {
+        let mut record = output.to_le_bytes().to_vec();
+        record.extend(vec![0; mtime_size]);
+        for input in inputs {
+            record.extend(input.to_le_bytes());
+        }
+        (true, record)
+    }
+
+    fn parsed(bytes: &[u8]) -> std::io::Result<Vec<(String, Vec<String>)>> {
+        let to_string = |p: &PathBuf| p.to_string_lossy().into_owned();
+        Ok(parse(bytes, Path::new("/build"))?
+            .iter()
+            .map(|d| {
+                (
+                    to_string(&d.source),
+                    d.headers.iter().map(to_string).collect(),
+                )
+            })
+            .collect())
+    }
+
+    #[test]
+    fn parse_records() {
+        let records = |mtime_size| {
+            vec![
+                path("a.o"),
+                path("../src/a.cpp"),
+                path("/usr/include/stdio.h"),
+                deps(mtime_size, 0, &[1, 2]),
+                path("b.o"),
+                path("b.cpp"),
+                deps(mtime_size, 3, &[4]),
+            ]
+        };
+        let expected = vec![
+            (
+                "/build/../src/a.cpp".to_string(),
+                vec!["/usr/include/stdio.h".to_string()],
+            ),
+            ("/build/b.cpp".to_string(), vec![]),
+        ];
+        for (version, mtime_size) in [(3, 4), (4, 8)] {
+            let bytes = log(version, &records(mtime_size));
+            assert_eq!(parsed(&bytes).unwrap(), expected, "version {}", version);
+        }
+    }
+
+    #[test]
+    fn parse_later_and_truncated_records() {
+        let mut bytes = log(
+            4,
+            &[
+                path("a.o"),
+                path("a.cpp"),
+                path("a.h"),
+                deps(8, 0, &[1, 2]),
+                deps(8, 0, &[1]),
+                path("b.o"),
+            ],
+        );
+        // an interrupted build leaves half a record
+        bytes.extend(0x8000_0010u32.to_le_bytes());
+        bytes.extend([0; 6]);
+        assert_eq!(
+            parsed(&bytes).unwrap(),
+            vec![("/build/a.cpp".to_string(), vec![])]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_logs() {
+        let cases: Vec<(Vec<u8>, &str)> = vec![
+            (b"# ninja log v5\n".to_vec(), "not a ninja deps log"),
+            (SIGNATURE.to_vec(), "truncated ninja deps log"),
+            (log(2, &[]), "unsupported ninja deps log version 2"),
+            (log(4, &[deps(0, 0, &[])]), "malformed deps record"),
+            (log(4, &[(true, vec![0; 14])]), "malformed deps record"),
+        ];
+        for (bytes, message) in cases {
+            let error = parsed(&bytes).unwrap_err();
+            assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), message);
+        }
+    }
+}
@@ -0,0 +1,815 @@
+//! Evaluation of preprocessor conditions for a single translation unit.
+//!
+//! Only simple expressions are supported: integer arithmetic, `defined` and object-like macros.
+//! Anything else (function-like macros, `__has_include`, ...) evaluates to `None`, in which case
+//! the branch is conservatively treated as active. So do identifiers whose definition may have
+//! been missed, see `Macros::is_defined`.
+
+use crate::scanner::{ConditionStack, Directive};
+use std::collections::{HashMap, HashSet};
+
+#[derive(Clone, Debug, Default)]
+pub struct Macros {
+    definitions: HashMap<String, Macro>,
+    /// macros known not to be defined, by `#undef`, `-U` or because the compiler doesn't
+    undefined: HashSet<String>,
+    /// Whether every include so far was walked, so that no definition can have been missed.
+    complete: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Macro {
+    function_like: bool,
+    body: String,
+}
+
+impl Macros {
+    /// Macros of a translation unit: unknown identifiers evaluate to `0` as in the compiler, until
+    /// an include can't be walked.
+    pub fn new() -> Macros {
+        Macros {
+            complete: true,
+            ..Macros::default()
+        }
+    }
+
+    /// Macros of no particular translation unit: only conditions that don't depend on macros
+    /// can be evaluated.
+    pub fn unknown() -> Macros {
+        Macros::default()
+    }
+
+    /// Called when an include leads to a file that is not walked, like a generated header that is
+    /// missing, whose definitions are then unknown.
+    pub fn set_incomplete(&mut self) {
+        self.complete = false;
+    }
+
+    /// Adds the macros a compiler defines by itself, as far as they commonly guard includes, and
+    /// marks the ones of other compilers and platforms as undefined.
+    pub fn add_predefined(&mut self, compiler: &str, cplusplus: bool) {
+        let compiler = compiler
+            .rsplit(['/', '\\'])
+            .next()
+            .unwrap_or(compiler)
+            .to_lowercase();
+        let compiler = compiler.trim_end_matches(".exe");
+        if compiler == "cl" || compiler == "clang-cl" {
+            self.define("_WIN32", "1");
+            self.define("_WIN64", "1");
+            self.define("_MSC_VER", "1920");
+            self.predefine_if("__clang__", compiler == "clang-cl");
+            // MSVC keeps reporting C++98 unless asked for /Zc:__cplusplus
+            self.predefine_if_with("__cplusplus", cplusplus, "199711L");
+            for name in ["__GNUC__", "__unix__", "__linux__", "__APPLE__"] {
+                self.undefine(name);
+            }
+        } else {
+            self.define("__GNUC__", "4");
+            self.predefine_if("__clang__", compiler.contains("clang"));
+            self.predefine_if_with("__cplusplus", cplusplus, "201703L");
+            self.undefine("_MSC_VER");
+            if cfg!(windows) {
+                self.define("_WIN32", "1");
+                self.undefine("__unix__");
+            } else {
+                self.define("__unix__", "1");
+                self.undefine("_WIN32");
+                self.undefine("_WIN64");
+            }
+            self.predefine_if("__linux__", cfg!(target_os = "linux"));
+            self.predefine_if("__APPLE__", cfg!(target_os = "macos"));
+        }
+    }
+
+    fn predefine_if(&mut self, name: &str, condition: bool) {
+        self.predefine_if_with(name, condition, "1");
+    }
+
+    fn predefine_if_with(&mut self, name: &str, condition: bool, body: &str) {
+        if condition {
+            self.define(name, body);
+        } else {
+            self.undefine(name);
+        }
+    }
+
+    pub fn define(&mut self, name: &str, body: &str) {
+        self.undefined.remove(name);
+        self.definitions.insert(
+            name.to_string(),
+            Macro {
+                function_like: false,
+                body: body.to_string(),
+            },
+        );
+    }
+
+    /// Applies a command line `-D` argument like `NAME` or `NAME=value`.
+    pub fn define_from_command_line(&mut self, definition: &str) {
+        match definition.find('=') {
+            Some(idx) => self.define(&definition[..idx], &definition[idx + 1..]),
+            None => self.define(definition, "1"),
+        }
+    }
+
+    pub fn undefine(&mut self, name: &str) {
+        self.definitions.remove(name);
+        self.undefined.insert(name.to_string());
+    }
+
+    /// Whether a macro is defined, or `None` if its definition may have been missed. Compilers
+    /// predefine more than `add_predefined` knows, so reserved names like `__x86_64__` are only
+    /// known to be undefined if marked as such.
+    fn is_defined(&self, name: &str) -> Option<bool> {
+        if self.definitions.contains_key(name) {
+            Some(true)
+        } else if self.undefined.contains(name) || (self.complete && !is_reserved(name)) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether an identifier is reserved for the implementation: `__x` or `_X`.
+fn is_reserved(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next() == Some('_')
+        && chars
+            .next()
+            .is_some_and(|c| c == '_' || c.is_ascii_uppercase())
+}
+
+/// Walks the directives of a file and calls `on_include` for every include that is active under
+/// `macros`, in source order. `#define` and `#undef` are applied to `macros` along the way, so
+/// that `on_include` can recurse into the included file with the state at the point of inclusion.
+pub fn walk(
+    directives: &[Directive],
+    macros: &mut Macros,
+    on_include: &mut dyn FnMut(usize, &mut Macros),
+) {
+    let mut conditions = ConditionStack::default();
+    for directive in directives {
+        match directive {
+            Directive::If(expression) => {
+                let condition = if conditions.is_active() {
+                    evaluate(expression, macros)
+                } else {
+                    None
+                };
+                conditions.push_if(condition);
+            }
+            Directive::Ifdef(name) | Directive::Ifndef(name) => {
+                let condition = if conditions.is_active() {
+                    macros.is_defined(name)
+                } else {
+                    None
+                };
+                let negate = matches!(directive, Directive::Ifndef(_));
+                conditions.push_if(condition.map(|c| c != negate));
+            }
+            Directive::Elif(expression) => {
+                let condition = if conditions.parent_active() {
+                    evaluate(expression, macros)
+                } else {
+                    None
+                };
+                conditions.elif(condition);
+            }
+            Directive::Else => conditions.else_(),
+            Directive::Endif => conditions.endif(),
+            _ if !conditions.is_active() => {}
+            Directive::Define {
+                name,
+                function_like,
+                body,
+            } => {
+                macros.undefined.remove(name);
+                macros.definitions.insert(
+                    name.clone(),
+                    Macro {
+                        function_like: *function_like,
+                        body: body.clone(),
+                    },
+                );
+            }
+            Directive::Undef(name) => macros.undefine(name),
+            Directive::Include(include) => on_include(*include, macros),
+        }
+    }
+}
+
+/// Returns the includes that sit under a condition that depends on the macros of a translation
+/// unit, apart from an include guard. Whether a build pulls them in can't be told from the file.
+pub fn conditional_includes(directives: &[Directive]) -> HashSet<usize> {
+    let unknown = Macros::unknown();
+    let guard = match directives {
+        [Directive::Ifndef(guard), Directive::Define { name, .. }, ..] if guard == name => true,
+        [Directive::If(condition), Directive::Define { name, .. }, ..] => {
+            let condition: String = condition.split_whitespace().collect();
+            condition == format!("!defined({})", name) || condition == format!("!defined{}", name)
+        }
+        _ => false,
+    };
+    // for each open conditional, whether the current branch is undetermined
+    let mut undetermined: Vec<bool> = vec![];
+    let mut includes = HashSet::new();
+    for (i, directive) in directives.iter().enumerate() {
+        match directive {
+            _ if i == 0 && guard => undetermined.push(false),
+            Directive::If(expression) => {
+                undetermined.push(evaluate(expression, &unknown).is_none());
+            }
+            Directive::Ifdef(_) | Directive::Ifndef(_) => undetermined.push(true),
+            Directive::Elif(expression) => {
+                if let Some(last) = undetermined.last_mut() {
+                    *last = *last || evaluate(expression, &unknown).is_none();
+                }
+            }
+            Directive::Endif => {
+                undetermined.pop();
+            }
+            Directive::Include(include) if undetermined.contains(&true) => {
+                includes.insert(*include);
+            }
+            _ => {}
+        }
+    }
+    includes
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Number(i64),
+    Identifier(String),
+    Punct(&'static str),
+    /// a value that can't be determined, like a macro that may be defined by a missed header
+    Unknown,
+}
+
+/// The comma only occurs in the arguments of function-like macros, which aren't evaluated.
+const PUNCTUATORS: [&str; 25] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "(", ")", "!", "~", "+", "-", "*", "/", "%",
+    "<", ">", "&", "^", "|", "?", ":", ",",
+];
+
+fn tokenize(expression: &str) -> Option<Vec<Token>> {
+    let bytes = expression.as_bytes();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+        if b.is_ascii_whitespace() {
+            i += 1;
+        } else if b.is_ascii_digit() {
+            let start = i;
+            while i < bytes.len()
+                && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'\'' || bytes[i] == b'.')
+            {
+                i += 1;
+            }
+            tokens.push(Token::Number(parse_number(&expression[start..i])?));
+        } else if b.is_ascii_alphabetic() || b == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token::Identifier(expression[start..i].to_string()));
+        } else if b == b'\'' {
+            let end = i + 1 + expression[i + 1..].find('\'')?;
+            tokens.push(Token::Number(parse_char(&expression[i + 1..end])?));
+            i = end + 1;
+        } else {
+            let punct = PUNCTUATORS
+                .iter()
+                .find(|p| expression[i..].starts_with(*p))?;
+            tokens.push(Token::Punct(punct));
+            i += punct.len();
+        }
+    }
+    Some(tokens)
+}
+
+fn parse_number(literal: &str) -> Option<i64> {
+    let literal = literal.replace('\'', "");
+    let digits = literal.trim_end_matches(['u', 'U', 'l', 'L']);
+    let (digits, radix) = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        (hex, 16)
+    } else if let Some(bin) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        (bin, 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (&digits[1..], 8)
+    } else {
+        (digits, 10)
+    };
+    u64::from_str_radix(digits, radix).ok().map(|v| v as i64)
+}
+
+fn parse_char(literal: &str) -> Option<i64> {
+    let value = match literal.as_bytes() {
+        [c] => *c,
+        [b'\\', b'n'] => b'\n',
+        [b'\\', b't'] => b'\t',
+        [b'\\', b'0'] => 0,
+        [b'\\', c] => *c,
+        _ => return None,
+    };
+    Some(i64::from(value))
+}
+
+/// Replaces macros and `defined` by their values, until only numbers, unknowns and operators
+/// remain. Returns `None` if the expression is malformed.
+fn expand(
+    tokens: &[Token],
+    macros: &Macros,
+    expanding: &mut HashSet<String>,
+) -> Option<Vec<Token>> {
+    let mut result = vec![];
+    let mut i = 0;
+    while i < tokens.len() {
+        let name = match &tokens[i] {
+            Token::Identifier(name) => name,
+            t => {
+                result.push(t.clone());
+                i += 1;
+                continue;
+            }
+        };
+        i += 1;
+        let followed_by_paren = tokens.get(i) == Some(&Token::Punct("("));
+        if name == "defined" {
+            let name = match (tokens.get(i), tokens.get(i + 1), tokens.get(i + 2)) {
+                (Some(Token::Identifier(name)), _, _) => {
+                    i += 1;
+                    name
+                }
+                (
+                    Some(Token::Punct("(")),
+                    Some(Token::Identifier(name)),
+                    Some(Token::Punct(")")),
+                ) => {
+                    i += 3;
+                    name
+                }
+                _ => return None,
+            };
+            result.push(match macros.is_defined(name) {
+                Some(defined) => Token::Number(defined as i64),
+                None => Token::Unknown,
+            });
+        } else if let Some(m) = macros
+            .definitions
+            .get(name)
+            .filter(|_| !expanding.contains(name))
+        {
+            if m.function_like {
+                if followed_by_paren {
+                    i = skip_arguments(tokens, i)?;
+                    result.push(Token::Unknown);
+                } else {
+                    // a function-like macro without arguments is not expanded
+                    result.push(Token::Number(0));
+                }
+                continue;
+            }
+            expanding.insert(name.clone());
+            let expanded = expand(&tokenize(&m.body)?, macros, expanding);
+            expanding.remove(name);
+            result.extend(expanded?);
+        } else if followed_by_paren {
+            // __has_include(...), unknown function-like macros, ...
+            i = skip_arguments(tokens, i)?;
+            result.push(Token::Unknown);
+        } else if name == "true" {
+            result.push(Token::Number(1));
+        } else if name == "false"
+            || expanding.contains(name)
+            || macros.is_defined(name) == Some(false)
+        {
+            // a macro within its own expansion is not expanded again
+            result.push(Token::Number(0));
+        } else {
+            result.push(Token::Unknown);
+        }
+    }
+    Some(result)
+}
+
+/// Returns the position after the parenthesized arguments that start at `start`.
+fn skip_arguments(tokens: &[Token], start: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token {
+            Token::Punct("(") => depth += 1,
+            Token::Punct(")") if depth == 1 => return Some(i + 1),
+            Token::Punct(")") => depth -= 1,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Expands the argument of a computed include like `#include PLATFORM_HEADER`, if it names an
+/// object-like macro that expands to a `"path"` or `<path>` literal, possibly through other such
+/// macros.
+pub fn expand_include<'a>(argument: &'a str, macros: &'a Macros) -> Option<&'a str> {
+    let mut text = argument.trim();
+    let mut expanding = HashSet::new();
+    while !text.starts_with(&['"', '<'][..]) {
+        let is_identifier = text.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_')
+            && !text.starts_with(|c: char| c.is_ascii_digit());
+        if !is_identifier || !expanding.insert(text) {
+            return None;
+        }
+        let m = macros.definitions.get(text)?;
+        if m.function_like {
+            return None;
+        }
+        text = m.body.trim();
+    }
+    Some(text)
+}
+
+/// Evaluates the expression of an `#if` or `#elif`.
+pub fn evaluate(expression: &str, macros: &Macros) -> Option<bool> {
+    let tokens = expand(&tokenize(expression)?, macros, &mut HashSet::new())?;
+    let mut parser = Parser { tokens, pos: 0 };
+    let value = parser.conditional()?;
+    if parser.pos != parser.tokens.len() {
+        return None;
+    }
+    value.map(|v| v != 0)
+}
+
+/// The value of a subexpression: `None` if it can't be determined, or would be undefined like a
+/// division by zero. As in the compiler, operands that `&&`, `||` and `?:` don't evaluate don't
+/// matter.
+type Value = Option<i64>;
+
+/// Parses expanded tokens, returning `None` on a syntax error.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_punct(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Punct(p)) => Some(p),
+            _ => None,
+        }
+    }
+
+    fn conditional(&mut self) -> Option<Value> {
+        let condition = self.binary(0)?;
+        if self.peek_punct() != Some("?") {
+            return Some(condition);
+        }
+        self.pos += 1;
+        let if_true = self.conditional()?;
+        if self.peek_punct() != Some(":") {
+            return None;
+        }
+        self.pos += 1;
+        let if_false = self.conditional()?;
+        Some(match condition {
+            Some(c) if c != 0 => if_true,
+            Some(_) => if_false,
+            None if if_true == if_false => if_true,
+            None => None,
+        })
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Option<Value> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek_punct() {
+            let precedence = match precedence(op) {
+                Some(p) if p >= min_precedence => p,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.binary(precedence + 1)?;
+            lhs = apply(op, lhs, rhs);
+        }
+        Some(lhs)
+    }
+
+    fn unary(&mut self) -> Option<Value> {
+        let token = self.tokens.get(self.pos)?.clone();
+        self.pos += 1;
+        match token {
+            Token::Number(n) => Some(Some(n)),
+            Token::Unknown => Some(None),
+            Token::Punct("(") => {
+                let value = self.conditional()?;
+                if self.peek_punct() != Some(")") {
+                    return None;
+                }
+                self.pos += 1;
+                Some(value)
+            }
+            Token::Punct("!") => Some(self.unary()?.map(|v| (v == 0) as i64)),
+            Token::Punct("~") => Some(self.unary()?.map(|v| !v)),
+            Token::Punct("-") => Some(self.unary()?.map(i64::wrapping_neg)),
+            Token::Punct("+") => self.unary(),
+            _ => None,
+        }
+    }
+}
+
+fn precedence(op: &str) -> Option<u8> {
+    Some(match op {
+        "||" => 0,
+        "&&" => 1,
+        "|" => 2,
+        "^" => 3,
+        "&" => 4,
+        "==" | "!=" => 5,
+        "<" | ">" | "<=" | ">=" => 6,
+        "<<" | ">>" => 7,
+        "+" | "-" => 8,
+        "*" | "/" | "%" => 9,
+        _ => return None,
+    })
+}
+
+fn apply(op: &str, lhs: Value, rhs: Value) -> Value {
+    // either side decides, whatever the other one is
+    match (op, lhs, rhs) {
+        ("||", Some(v), _) | ("||", _, Some(v)) if v != 0 => return Some(1),
+        ("&&", Some(0), _) | ("&&", _, Some(0)) => return Some(0),
+        _ => {}
+    }
+    let (lhs, rhs) = (lhs?, rhs?);
+    Some(match op {
+        "||" => (lhs != 0 || rhs != 0) as i64,
+        "&&" => (lhs != 0 && rhs != 0) as i64,
+        "|" => lhs | rhs,
+        "^" => lhs ^ rhs,
+        "&" => lhs & rhs,
+        "==" => (lhs == rhs) as i64,
+        "!=" => (lhs != rhs) as i64,
+        "<" => (lhs < rhs) as i64,
+        ">" => (lhs > rhs) as i64,
+        "<=" => (lhs <= rhs) as i64,
+        ">=" => (lhs >= rhs) as i64,
+        "<<" => lhs.wrapping_shl(rhs as u32),
+        ">>" => lhs.wrapping_shr(rhs as u32),
+        "+" => lhs.wrapping_add(rhs),
+        "-" => lhs.wrapping_sub(rhs),
+        "*" => lhs.wrapping_mul(rhs),
+        "/" => lhs.checked_div(rhs)?,
+        "%" => lhs.checked_rem(rhs)?,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn macros(complete: bool) -> Macros {
+        let mut macros = if complete {
+            Macros::new()
+        } else {
+            Macros::unknown()
+        };
+        macros.define("A", "1");
+        macros.define("B", "0");
+        macros.define("VERSION", "0x0600");
+        macros.define("RECURSIVE", "RECURSIVE + 1");
+        macros.definitions.insert(
+            "F".to_string(),
+            Macro {
+                function_like: true,
+                body: "x".to_string(),
+            },
+        );
+        macros
+    }
+
+    #[test]
+    fn evaluate_expressions() {
+        let cases: &[(&str, Option<bool>, Option<bool>)] = &[
+            // expression, with complete macros, with incomplete macros
+            ("defined A", Some(true), Some(true)),
+            ("defined(A)", Some(true), Some(true)),
+            ("defined ( A )", Some(true), Some(true)),
+            ("!defined MISSING", Some(true), None),
+            ("defined MISSING || A", Some(true), Some(true)),
+            ("MISSING", Some(false), None),
+            ("__x86_64__", None, None),
+            ("A ? 2 : 0", Some(true), Some(true)),
+            ("B ? 1 : 0", Some(false), Some(false)),
+            ("A ? B ? 1 : 0 : 1", Some(false), Some(false)),
+            ("MISSING ? 1 : 1", Some(true), Some(true)),
+            ("0 && 1 / 0", Some(false), Some(false)),
+            ("1 || 1 / 0", Some(true), Some(true)),
+            ("1 / 0", None, None),
+            ("B && MISSING", Some(false), Some(false)),
+            ("A || F(1, (2))", Some(true), Some(true)),
+            ("F(1)", None, None),
+            ("F", Some(false), Some(false)),
+            ("1 + 2 * 3 == 7", Some(true), Some(true)),
+            ("(1 + 2) * 3 == 9", Some(true), Some(true)),
+            ("1 - 1 - 1 == -1", Some(true), Some(true)),
+            ("1 << 4 == 16 && 2 > 1 == 1", Some(true), Some(true)),
+            (
+                "VERSION >= 0x0500 && 010 == 8 && 0b11 == 3",
+                Some(true),
+                Some(true),
+            ),
+            ("'a' == 97 && 10UL == 10", Some(true), Some(true)),
+            ("RECURSIVE", Some(true), Some(true)),
+            ("true && !false", Some(true), Some(true)),
+            ("1 +", None, None),
+            ("(1", None, None),
+            ("defined", None, None),
+            ("A ? 1", None, None),
+        ];
+        for &(expression, complete, incomplete) in cases {
+            assert_eq!(
+                evaluate(expression, &macros(true)),
+                complete,
+                "{}",
+                expression
+            );
+            assert_eq!(
+                evaluate(expression, &macros(false)),
+                incomplete,
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn expand_macros() {
+        use Token::*;
+        let cases: &[(&str, Option<Vec<Token>>)] = &[
+            (
+                "defined A && B",
+                Some(vec![Number(1), Punct("&&"), Number(0)]),
+            ),
+            ("F(1, (2)) + 2", Some(vec![Unknown, Punct("+"), Number(2)])),
+            ("VERSION", Some(vec![Number(0x600)])),
+            ("RECURSIVE", Some(vec![Number(0), Punct("+"), Number(1)])),
+            ("__has_include(x)", Some(vec![Unknown])),
+            ("F(1", None),
+            ("defined + 1", None),
+        ];
+        for (expression, expected) in cases {
+            let tokens = tokenize(expression).unwrap();
+            assert_eq!(
+                &expand(&tokens, &macros(true), &mut HashSet::new()),
+                expected,
+                "{}",
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn parse_unknowns() {
+        use Token::*;
+        let cases: Vec<(Vec<Token>, Option<Value>)> = vec![
+            (vec![Unknown, Punct("+"), Number(1)], Some(None)),
+            (vec![Unknown, Punct("&&"), Number(0)], Some(Some(0))),
+            (vec![Number(2), Punct("||"), Unknown], Some(Some(1))),
+            (
+                vec![Unknown, Punct("?"), Number(1), Punct(":"), Number(1)],
+                Some(Some(1)),
+            ),
+            (vec![Punct("!"), Unknown], Some(None)),
+            (vec![Unknown, Punct(")")], Some(None)),
+            (vec![Punct("*"), Number(1)], None),
+        ];
+        for (tokens, expected) in cases {
+            let mut parser = Parser {
+                tokens: tokens.clone(),
+                pos: 0,
+            };
+            assert_eq!(parser.conditional(), expected, "{:?}", tokens);
+        }
+    }
+
+    #[test]
+    fn predefined_macros() {
+        let cases: &[(&str, bool, &str, Option<bool>)] = &[
+            ("/usr/bin/g++", true, "__cplusplus >= 201103L", Some(true)),
+            ("gcc", false, "defined __cplusplus", Some(false)),
+            (
+                "clang++",
+                true,
+                "defined __clang__ && !defined _MSC_VER",
+                Some(true),
+            ),
+            (
+                "C:\\VS\\cl.exe",
+                true,
+                "defined _WIN64 && __cplusplus == 199711L",
+                Some(true),
+            ),
+            (
+                "cl",
+                false,
+                "defined __GNUC__ || defined __linux__",
+                Some(false),
+            ),
+            ("cl", false, "defined __clang__", Some(false)),
+            ("clang-cl", false, "defined __clang__", Some(true)),
+            ("cl", false, "defined _M_X64", None),
+        ];
+        for &(compiler, cplusplus, expression, expected) in cases {
+            let mut macros = Macros::unknown();
+            macros.add_predefined(compiler, cplusplus);
+            assert_eq!(
+                evaluate(expression, &macros),
+                expected,
+                "{} {}",
+                compiler,
+                expression
+            );
+        }
+    }
+
+    #[test]
+    fn find_conditional_includes() {
+        use Directive::*;
+        let define = |name: &str| Define {
+            name: name.to_string(),
+            function_like: false,
+            body: String::new(),
+        };
+        let s = |s: &str| s.to_string();
+        let cases: Vec<(Vec<Directive>, Vec<usize>)> = vec![
+            (vec![Include(0), Ifdef(s("A")), Include(1), Endif], vec![1]),
+            (
+                vec![If(s("1")), Include(0), Else, Endif, Include(1)],
+                vec![],
+            ),
+            (
+                vec![If(s("A")), Include(0), Elif(s("1")), Include(1), Endif],
+                vec![0, 1],
+            ),
+            (vec![If(s("0")), Elif(s("B")), Include(0), Endif], vec![0]),
+            (
+                vec![Ifndef(s("A")), Include(0), Else, Include(1), Endif],
+                vec![0, 1],
+            ),
+            // include guards
+            (vec![Ifndef(s("G")), define("G"), Include(0), Endif], vec![]),
+            (
+                vec![
+                    If(s("!defined( G )")),
+                    define("G"),
+                    Include(0),
+                    Ifdef(s("A")),
+                    Include(1),
+                    Endif,
+                    Endif,
+                ],
+                vec![1],
+            ),
+            (
+                vec![Ifndef(s("G")), define("H"), Include(0), Endif],
+                vec![0],
+            ),
+        ];
+        for (directives, expected) in cases {
+            let mut includes: Vec<usize> = conditional_includes(&directives).into_iter().collect();
+            includes.sort_unstable();
+            assert_eq!(includes, expected, "{:?}", directives);
+        }
+    }
+
+    #[test]
+    fn walk_after_missed_include() {
+        // #include "config.h" (outside of the tree), then #ifdef HAVE_FEATURE #include ...
+        let directives = [
+            Directive::Include(0),
+            Directive::Ifdef("HAVE_FEATURE".to_string()),
+            Directive::Include(1),
+            Directive::Else,
+            Directive::Include(2),
+            Directive::Endif,
+        ];
+        for (missed, expected) in [(false, vec![0, 2]), (true, vec![0, 1, 2])] {
+            let mut includes = vec![];
+            walk(&directives, &mut Macros::new(), &mut |include, macros| {
+                if include == 0 && missed {
+                    macros.set_incomplete();
+                }
+                includes.push(include);
+            });
+            assert_eq!(includes, expected);
+        }
+    }
+}
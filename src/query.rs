@@ -0,0 +1,272 @@
+//! Queries on the graph that return data, for front ends to present.
+
+use crate::diagnostics::{Category, Diagnostic};
+use crate::error::Result;
+use crate::graph::{self, ComponentRef, Edge, FileRef, Graph};
+use std::collections::HashMap;
+
+/// The components a component links with, each with the edges between them. Components are in
+/// the order of `Component::sort_key`.
+pub struct ComponentLinks {
+    pub incoming: Vec<(ComponentRef, Vec<Edge>)>,
+    pub outgoing: Vec<(ComponentRef, Vec<Edge>)>,
+}
+
+/// The headers of a component by how they are included. Each list is ordered by path.
+#[derive(Default)]
+pub struct HeaderClasses {
+    /// files included from other components or by public files, with those includers
+    pub public: Vec<(FileRef, Vec<FileRef>)>,
+    /// headers included only by private files of their own component
+    pub private: Vec<FileRef>,
+    /// headers included once, by a similarly named file, with that file
+    pub solo: Vec<(FileRef, FileRef)>,
+    /// headers that nothing includes
+    pub dead: Vec<FileRef>,
+}
+
+impl Graph {
+    pub fn file_name_to_ref(&self, file_name: &str) -> Result<FileRef> {
+        self.files
+            .iter()
+            .position(|f| f.path == file_name)
+            .ok_or_else(|| graph::not_found("file", file_name))
+    }
+
+    /// Like `linked_components`, but ordered.
+    pub fn component_links(&self, c: ComponentRef, only_public: bool) -> ComponentLinks {
+        let (incoming, outgoing) = self.linked_components(c, only_public);
+        ComponentLinks {
+            incoming: self.sorted_by_component(incoming),
+            outgoing: self.sorted_by_component(outgoing),
+        }
+    }
+
+    fn sorted_by_component(
+        &self,
+        links: HashMap<ComponentRef, Vec<Edge>>,
+    ) -> Vec<(ComponentRef, Vec<Edge>)> {
+        let mut links: Vec<(ComponentRef, Vec<Edge>)> = links.into_iter().collect();
+        links.sort_by(|(a, _), (b, _)| {
+            self.components[*a]
+                .sort_key()
+                .cmp(&self.components[*b].sort_key())
+        });
+        links
+    }
+
+    /// Tells which headers of a component are public, private, solo or dead.
+    pub fn classify_headers(&self, c: ComponentRef) -> HeaderClasses {
+        let mut classes = HeaderClasses::default();
+        for &file_ref in &self.component_files[c] {
+            let links = &self.file_links[file_ref].incoming_links;
+            let public_links: Vec<FileRef> = links
+                .iter()
+                .filter(|&f_ref| self.file_components[*f_ref] != c || self.file_is_public[*f_ref])
+                .cloned()
+                .collect();
+            if !public_links.is_empty() {
+                classes.public.push((file_ref, public_links));
+                continue;
+            }
+
+            if self.is_header(file_ref) {
+                if !links.is_empty() {
+                    if links.len() == 1 {
+                        let fi = links[0];
+                        let base_name = self.files[file_ref].path.rsplit('/').next().unwrap();
+                        if let Some(base_name) = base_name.rsplit('.').nth(1) {
+                            if self.files[fi].path.contains(base_name) {
+                                // solo header: file included only once, by a similarly-named source file
+                                classes.solo.push((file_ref, fi));
+                                continue;
+                            }
+                        }
+                    }
+                    classes.private.push(file_ref);
+                } else {
+                    classes.dead.push(file_ref);
+                }
+            }
+        }
+
+        let path = |f: FileRef| &self.files[f].path;
+        classes
+            .public
+            .sort_by(|(a, _), (b, _)| path(*a).cmp(path(*b)));
+        classes.private.sort_by(|a, b| path(*a).cmp(path(*b)));
+        classes
+            .solo
+            .sort_by(|(a, _), (b, _)| path(*a).cmp(path(*b)));
+        classes.dead.sort_by(|a, b| path(*a).cmp(path(*b)));
+        classes
+    }
+
+    /// Returns the components on a shortest path of links from `c_from` to `c_to`, both
+    /// included, or `None` if there is no path. With `only_public`, the path must start at a
+    /// public file of `c_from`.
+    pub fn shortest_path(
+        &self,
+        c_from: ComponentRef,
+        c_to: ComponentRef,
+        only_public: bool,
+    ) -> Option<Vec<ComponentRef>> {
+        let mut dists = vec![(0usize, u32::MAX); self.components.len()];
+        dists[c_from] = (c_from, 0);
+
+        let mut queue = std::collections::VecDeque::new();
+        queue.push_back(c_from);
+
+        while let Some(c_source) = queue.pop_front() {
+            let dist = dists[c_source].1 + 1;
+
+            for &f in self.component_files[c_source].iter() {
+                if c_source == c_from && only_public && !self.file_is_public[f] {
+                    continue;
+                }
+                for fo in self.file_links[f].outgoing_links.iter() {
+                    let c = self.file_components[*fo];
+                    if dists[c].1 > dist {
+                        dists[c] = (c_source, dist);
+                        queue.push_back(c);
+                    }
+                }
+            }
+        }
+
+        if dists[c_to].1 == u32::MAX {
+            return None;
+        }
+
+        let mut result = vec![];
+        let mut c = c_to;
+        while c != c_from {
+            result.push(c);
+            c = dists[c].0;
+        }
+        result.push(c_from);
+        result.reverse();
+        Some(result)
+    }
+
+    /// The file links from component `c` to component `c_to`, from public files of `c` only
+    /// with `only_public`.
+    pub fn links_between(
+        &self,
+        c: ComponentRef,
+        c_to: ComponentRef,
+        only_public: bool,
+    ) -> Vec<(FileRef, FileRef)> {
+        let mut links = vec![];
+        for &f in self.component_files[c].iter() {
+            if only_public && !self.file_is_public[f] {
+                continue;
+            }
+            for &fo in self.file_links[f].outgoing_links.iter() {
+                if self.file_components[fo] == c_to {
+                    links.push((f, fo));
+                }
+            }
+        }
+        links
+    }
+
+    /// The diagnostics of the given categories, or of all categories if none are given, grouped
+    /// by category in the order of `Category::ALL`. Categories without diagnostics are left out.
+    pub fn diagnostics_by_category(
+        &self,
+        categories: &[Category],
+    ) -> Vec<(Category, Vec<&Diagnostic>)> {
+        Category::ALL
+            .iter()
+            .filter(|c| categories.is_empty() || categories.contains(c))
+            .map(|&c| {
+                let diagnostics = self.diagnostics.iter().filter(|d| d.category == c);
+                (c, diagnostics.collect::<Vec<_>>())
+            })
+            .filter(|(_, diagnostics)| !diagnostics.is_empty())
+            .collect()
+    }
+
+    /// Returns the groups of components that link to each other in a cycle.
+    pub fn strongly_connected_components(&self) -> Vec<Vec<ComponentRef>> {
+        Tarjan::run(self)
+            .into_iter()
+            .filter(|c| c.len() > 1)
+            .map(|mut scc| {
+                scc.reverse();
+                scc
+            })
+            .collect()
+    }
+}
+
+struct Tarjan {
+    index: i32,
+    indices: Vec<i32>,
+    lowlink: Vec<i32>,
+    on_stack: Vec<bool>,
+    stack: Vec<ComponentRef>,
+    sccs: Vec<Vec<ComponentRef>>,
+}
+
+impl Tarjan {
+    fn run(project: &Graph) -> Vec<Vec<ComponentRef>> {
+        let mut t = Tarjan {
+            index: 0,
+            indices: std::iter::repeat_n(-1, project.components.len()).collect(),
+            lowlink: std::iter::repeat_n(-1, project.components.len()).collect(),
+            on_stack: std::iter::repeat_n(false, project.components.len()).collect(),
+            stack: vec![],
+            sccs: vec![],
+        };
+        for v in 0..project.components.len() {
+            if t.indices[v] == -1 {
+                t.strong_connect(v, project);
+            }
+        }
+        t.sccs
+    }
+
+    fn strong_connect(&mut self, v: ComponentRef, project: &Graph) {
+        // Set the depth index for v to the smallest unused index
+        self.indices[v] = self.index;
+        self.lowlink[v] = self.index;
+        self.index += 1;
+        self.stack.push(v);
+        self.on_stack[v] = true;
+
+        // Consider successors of v
+        for w in project.component_files[v]
+            .iter()
+            .flat_map(|&f| &project.file_links[f].outgoing_links)
+            .map(|f| project.file_components[*f])
+            .filter(|&c| c != v)
+        {
+            if self.indices[w] == -1 {
+                // Successor w has not yet been visited; recurse on it
+                self.strong_connect(w, project);
+                self.lowlink[v] = std::cmp::min(self.lowlink[v], self.lowlink[w]);
+            } else if self.on_stack[w] {
+                // Successor w is in stack S and hence in the current SCC
+                // If w is not on stack, then (v, w) is a cross-edge in the DFS tree and must be ignored
+                // Note: The next line may look odd - but is correct.
+                // It says w.index not w.lowlink; that is deliberate and from the original paper
+                self.lowlink[v] = std::cmp::min(self.lowlink[v], self.indices[w]);
+            }
+        }
+        // If v is a root node, pop the stack and generate an SCC
+        if self.lowlink[v] == self.indices[v] {
+            let mut scc = vec![];
+            loop {
+                let w = self.stack.pop().expect("empty stack?");
+                self.on_stack[w] = false;
+                scc.push(w);
+                if w == v {
+                    break;
+                }
+            }
+            self.sccs.push(scc);
+        }
+    }
+}
@@ -0,0 +1,306 @@
+//! The scan cache: scanned files of an earlier run, reused as long as their size and modification
+//! time stay the same. Along with a file, the cache keeps what its includes resolved to, which
+//! stays valid as long as no file is added or removed that an include could refer to.
+//!
+//! The cache is thrown away as a whole when it was written for another root, another scanner
+//! version or another language profile.
+
+use crate::file_collector::File;
+use crate::language::LanguageProfile;
+use crate::scanner;
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{self, BufReader, BufWriter};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// What a file looked like when it was scanned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    size: u64,
+    modified: SystemTime,
+}
+
+impl FileStamp {
+    pub fn of(metadata: &std::fs::Metadata) -> Option<FileStamp> {
+        Some(FileStamp {
+            size: metadata.len(),
+            modified: metadata.modified().ok()?,
+        })
+    }
+}
+
+/// For each include of a file, the paths of the files it may refer to, before any of them are
+/// ruled out by component. Empty for includes that refer to no file.
+pub type Resolution = Vec<Vec<String>>;
+
+type Entry = (FileStamp, File, Option<Resolution>);
+
+/// The layout of the cache file, which changes independently of the scanner.
+const FORMAT: u32 = 2;
+
+/// Describes what the cached scans depend on besides the files themselves.
+#[derive(Serialize, Deserialize, PartialEq)]
+struct Header {
+    format: u32,
+    version: u32,
+    root: String,
+    profile: LanguageProfile,
+}
+
+#[derive(Default)]
+pub struct ScanCache {
+    entries: Mutex<HashMap<String, Entry>>,
+    /// the resolutions of the files taken so far
+    resolutions: Mutex<HashMap<String, Resolution>>,
+    /// the paths of all cached files, which were the files of the tree when the cache was written
+    paths: HashSet<String>,
+}
+
+impl ScanCache {
+    /// Reads the cache at `path`. A cache that is missing, unreadable or was written for other
+    /// settings gives an empty cache.
+    pub fn load(path: &str, root: &str, profile: &LanguageProfile) -> ScanCache {
+        let f = match std::fs::File::open(path) {
+            Ok(f) => f,
+            Err(_) => return ScanCache::default(),
+        };
+        // a corrupt length must not make bincode allocate more than the file could hold
+        let size = f.metadata().map(|m| m.len()).unwrap_or(0);
+        let options = bincode::options()
+            .with_fixint_encoding()
+            .allow_trailing_bytes()
+            .with_limit(size);
+        let mut reader = BufReader::new(f);
+        let header: Header = match options.deserialize_from(&mut reader) {
+            Ok(header) => header,
+            Err(_) => return ScanCache::default(),
+        };
+        if header != Header::new(root, profile) {
+            return ScanCache::default();
+        }
+        let entries: Vec<Entry> = match options.deserialize_from(&mut reader) {
+            Ok(entries) => entries,
+            Err(_) => return ScanCache::default(),
+        };
+        ScanCache::from_entries(entries)
+    }
+
+    /// A cache of files scanned earlier in this run. `resolutions` may be shorter than `files`,
+    /// for a graph that was not loaded from the includes.
+    pub fn from_files(
+        files: &[File],
+        stamps: &[Option<FileStamp>],
+        resolutions: &[Option<Resolution>],
+    ) -> ScanCache {
+        let entries = stamps
+            .iter()
+            .zip(files)
+            .enumerate()
+            .filter_map(|(i, (stamp, file))| {
+                let resolution = resolutions.get(i).cloned().flatten();
+                Some(((*stamp)?, file.clone(), resolution))
+            })
+            .collect();
+        ScanCache::from_entries(entries)
+    }
+
+    fn from_entries(entries: Vec<Entry>) -> ScanCache {
+        let paths = entries
+            .iter()
+            .map(|(_, file, _)| file.path.clone())
+            .collect();
+        let entries = entries
+            .into_iter()
+            .map(|entry| (entry.1.path.clone(), entry))
+            .collect();
+        ScanCache {
+            entries: Mutex::new(entries),
+            resolutions: Mutex::default(),
+            paths,
+        }
+    }
+
+    /// Takes the cached scan of a root-relative path if the file has not changed since.
+    pub fn take(&self, path: &str, stamp: FileStamp) -> Option<File> {
+        let mut entries = self.entries.lock().unwrap();
+        match entries.remove(path) {
+            Some((cached, file, resolution)) if cached == stamp => {
+                if let Some(resolution) = resolution {
+                    let mut resolutions = self.resolutions.lock().unwrap();
+                    resolutions.insert(file.path.clone(), resolution);
+                }
+                Some(file)
+            }
+            _ => None,
+        }
+    }
+
+    /// Takes the cached resolution of a file that was taken with `take`.
+    pub(crate) fn take_resolution(&self, path: &str) -> Option<Resolution> {
+        self.resolutions.lock().unwrap().remove(path)
+    }
+
+    /// Whether the cache has any resolutions to reuse.
+    pub(crate) fn has_resolutions(&self) -> bool {
+        !self.resolutions.lock().unwrap().is_empty()
+    }
+
+    /// The paths of all cached files, whether they were taken or not.
+    pub(crate) fn paths(&self) -> &HashSet<String> {
+        &self.paths
+    }
+
+    /// Writes the scans of `files` and their resolutions to `path`. Files without a stamp are
+    /// left out.
+    pub fn save(
+        path: &str,
+        root: &str,
+        profile: &LanguageProfile,
+        files: &[File],
+        stamps: &[Option<FileStamp>],
+        resolutions: &[Option<Resolution>],
+    ) -> io::Result<()> {
+        let entries: Vec<(&FileStamp, &File, Option<&Resolution>)> = stamps
+            .iter()
+            .zip(files)
+            .enumerate()
+            .filter_map(|(i, (stamp, file))| {
+                let resolution = resolutions.get(i).and_then(Option::as_ref);
+                Some((stamp.as_ref()?, file, resolution))
+            })
+            .collect();
+        let mut writer = BufWriter::new(std::fs::File::create(path)?);
+        let to_io = |e: bincode::Error| io::Error::other(e);
+        bincode::serialize_into(&mut writer, &Header::new(root, profile)).map_err(to_io)?;
+        bincode::serialize_into(&mut writer, &entries).map_err(to_io)?;
+        Ok(())
+    }
+}
+
+impl Header {
+    fn new(root: &str, profile: &LanguageProfile) -> Header {
+        // the cache is only valid for the same tree, however it is spelled
+        let root = std::path::Path::new(root)
+            .canonicalize()
+            .map(|p| p.to_string_lossy().into_owned())
+            .unwrap_or_else(|_| root.to_string());
+        Header {
+            format: FORMAT,
+            version: scanner::VERSION,
+            root,
+            profile: profile.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding::Encoding;
+    use crate::test_tree::TestTree;
+    use std::time::Duration;
+
+    fn file(path: &str) -> File {
+        File {
+            path: path.to_string(),
+            includes: vec![],
+            directives: vec![],
+            module: None,
+            imports: vec![],
+            encoding: Encoding::Utf8,
+        }
+    }
+
+    fn stamp(size: u64, seconds: u64) -> FileStamp {
+        FileStamp {
+            size,
+            modified: SystemTime::UNIX_EPOCH + Duration::from_secs(seconds),
+        }
+    }
+
+    #[test]
+    fn take_unchanged_files() {
+        let files = [file("a.h"), file("b.h"), file("c.h")];
+        let stamps = [Some(stamp(10, 100)), Some(stamp(10, 100)), None];
+        let resolutions = [Some(vec![vec!["b.h".to_string()]]), None, None];
+        let cache = ScanCache::from_files(&files, &stamps, &resolutions);
+        assert!(!cache.has_resolutions());
+        // path, stamp now, whether the scan is taken
+        let cases = [
+            ("a.h", stamp(10, 100), true),
+            ("a.h", stamp(10, 100), false),
+            ("b.h", stamp(11, 100), false),
+            ("c.h", stamp(10, 100), false),
+            ("d.h", stamp(10, 100), false),
+        ];
+        for (path, now, expected) in cases {
+            let taken = cache.take(path, now);
+            assert_eq!(
+                taken.map(|f| f.path),
+                expected.then(|| path.to_string()),
+                "{}",
+                path
+            );
+        }
+        let cache = ScanCache::from_files(&files, &stamps, &resolutions);
+        assert!(cache.take("a.h", stamp(10, 101)).is_none());
+        assert!(cache.take_resolution("a.h").is_none());
+        let cache = ScanCache::from_files(&files, &stamps, &resolutions);
+        assert!(cache.take("a.h", stamp(10, 100)).is_some());
+        assert!(cache.has_resolutions());
+        assert_eq!(cache.take_resolution("a.h"), resolutions[0]);
+        let mut paths: Vec<&String> = cache.paths().iter().collect();
+        paths.sort();
+        assert_eq!(paths, ["a.h", "b.h"]);
+    }
+
+    #[test]
+    fn discard_caches_of_other_settings() {
+        let tree = TestTree::new("scan-cache", &[("src/a.h", b"")]);
+        let root = tree.root();
+        let path = tree.path("cache.bin");
+        let path = path.to_str().unwrap();
+        let profile = LanguageProfile::default();
+        let files = [file("src/a.h")];
+        let stamps = [Some(stamp(0, 100))];
+        ScanCache::save(path, root, &profile, &files, &stamps, &[]).unwrap();
+        let saved = std::fs::read(path).unwrap();
+
+        let is_loaded = |root: &str, profile: &LanguageProfile| {
+            let cache = ScanCache::load(path, root, profile);
+            cache.take("src/a.h", stamp(0, 100)).is_some()
+        };
+        assert!(is_loaded(root, &profile));
+        assert!(is_loaded(&format!("{}/src/..", root), &profile));
+        assert!(!is_loaded(tree.path("src").to_str().unwrap(), &profile));
+        let mut other = profile.clone();
+        other.headers.push(".inl2".to_string());
+        assert!(!is_loaded(root, &other));
+
+        // a header written by another layout or another scanner
+        let mut header = Header::new(root, &profile);
+        header.format += 1;
+        let mut written = bincode::serialize(&header).unwrap();
+        written.extend(&saved[bincode::serialized_size(&header).unwrap() as usize..]);
+        std::fs::write(path, &written).unwrap();
+        assert!(!is_loaded(root, &profile));
+        let mut header = Header::new(root, &profile);
+        header.version += 1;
+        let mut written = bincode::serialize(&header).unwrap();
+        written.extend(&saved[bincode::serialized_size(&header).unwrap() as usize..]);
+        std::fs::write(path, &written).unwrap();
+        assert!(!is_loaded(root, &profile));
+
+        // truncated, corrupt and missing files
+        std::fs::write(path, &saved[..saved.len() - 1]).unwrap();
+        assert!(!is_loaded(root, &profile));
+        std::fs::write(path, &saved[..4]).unwrap();
+        assert!(!is_loaded(root, &profile));
+        std::fs::write(path, vec![0xff; saved.len()]).unwrap();
+        assert!(!is_loaded(root, &profile));
+        std::fs::remove_file(path).unwrap();
+        assert!(!is_loaded(root, &profile));
+    }
+}
@@ -0,0 +1,828 @@
+//! A lightweight lexer pass over C/C++ sources.
+//!
+//! This is not a full preprocessor: it strips comments and literals, joins continued lines and
+//! tracks `#if`/`#else`/`#endif` nesting, which is enough to avoid picking up includes from
+//! commented-out code or `#if 0` blocks.
+
+use crate::preprocessor::{self, Macros};
+use serde::{Deserialize, Serialize};
+
+/// The version of the scan results. Bump it whenever a change to the scanner changes what it finds
+/// in a file, so that cached scans are thrown away.
+pub const VERSION: u32 = 2;
+
+/// A logical source line, i.e. physical lines joined by backslash continuations.
+pub struct Line {
+    /// the physical line the logical line starts on, counting from 1
+    pub number: usize,
+    pub text: String,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Code,
+    LineComment,
+    BlockComment,
+    String(u8),
+}
+
+/// Splits `text` into logical lines with comments removed and literal contents blanked out.
+///
+/// Removed characters are replaced by spaces, so columns on the first physical line of a logical
+/// line stay valid. Literals are kept verbatim on directive lines, because `#include "a.h"` needs
+/// its argument, and on lines starting with `import` or `export` for `import "a.h";`.
+pub fn logical_lines(text: &str) -> Vec<Line> {
+    let bytes = text.as_bytes();
+    let mut lines = vec![];
+    let mut current: Vec<u8> = vec![];
+    // decided at the first token of a logical line
+    let mut keep_literals: Option<bool> = None;
+    let mut state = State::Code;
+    // the physical line at `i`, and the one the current logical line started on
+    let mut number = 1;
+    let mut start = 1;
+
+    let mut i = 0;
+    while i < bytes.len() {
+        let b = bytes[i];
+
+        // line splicing happens before everything else
+        if b == b'\\' {
+            let next = match bytes.get(i + 1) {
+                Some(b'\r') if bytes.get(i + 2) == Some(&b'\n') => Some(i + 3),
+                Some(b'\n') => Some(i + 2),
+                _ => None,
+            };
+            if let Some(next) = next {
+                i = next;
+                number += 1;
+                continue;
+            }
+        }
+
+        if b == b'\n' {
+            i += 1;
+            number += 1;
+            match state {
+                // a block comment does not end the logical line
+                State::BlockComment => {
+                    if keep_literals.is_none() {
+                        // nothing but comments so far, start over on the next physical line
+                        current.clear();
+                        start = number;
+                    }
+                    continue;
+                }
+                // unterminated literals end at the line break
+                State::LineComment | State::String(_) => state = State::Code,
+                State::Code => {}
+            }
+            lines.push(Line {
+                number: start,
+                text: String::from_utf8_lossy(&current).into_owned(),
+            });
+            start = number;
+            current.clear();
+            keep_literals = None;
+            continue;
+        }
+
+        match state {
+            State::LineComment => current.push(b' '),
+            State::BlockComment => {
+                if b == b'*' && bytes.get(i + 1) == Some(&b'/') {
+                    current.extend_from_slice(b"  ");
+                    state = State::Code;
+                    i += 2;
+                    continue;
+                }
+                current.push(b' ');
+            }
+            State::String(delimiter) => {
+                let keep = keep_literals == Some(true);
+                if b == b'\\' && i + 1 < bytes.len() && bytes[i + 1] != b'\n' {
+                    if keep {
+                        current.extend_from_slice(&bytes[i..i + 2]);
+                    } else {
+                        current.extend_from_slice(b"  ");
+                    }
+                    i += 2;
+                    continue;
+                }
+                if b == delimiter {
+                    current.push(b);
+                    state = State::Code;
+                } else if keep {
+                    current.push(b);
+                } else if b != b'\r' {
+                    current.push(b' ');
+                }
+            }
+            State::Code => {
+                let starts_comment = b == b'/' && matches!(bytes.get(i + 1), Some(b'/' | b'*'));
+                if keep_literals.is_none() && !starts_comment && !b.is_ascii_whitespace() {
+                    let token = following_token(bytes, i);
+                    keep_literals = Some(b == b'#' || token == b"import" || token == b"export");
+                }
+                match b {
+                    b'/' if bytes.get(i + 1) == Some(&b'/') => {
+                        current.extend_from_slice(b"  ");
+                        state = State::LineComment;
+                        i += 2;
+                        continue;
+                    }
+                    b'/' if bytes.get(i + 1) == Some(&b'*') => {
+                        current.extend_from_slice(b"  ");
+                        state = State::BlockComment;
+                        i += 2;
+                        continue;
+                    }
+                    b'"' => {
+                        if let Some(end) = raw_string_end(bytes, i) {
+                            // raw strings may span lines without ending the logical line
+                            current.push(b'"');
+                            let contents = &bytes[i + 1..end - 1];
+                            number += contents.iter().filter(|&&c| c == b'\n').count();
+                            current.extend(contents.iter().filter(|&&c| c != b'\n').map(|_| b' '));
+                            current.push(b'"');
+                            i = end;
+                            continue;
+                        }
+                        current.push(b);
+                        state = State::String(b'"');
+                    }
+                    b'\'' if !is_digit_separator(bytes, i) => {
+                        current.push(b);
+                        state = State::String(b'\'');
+                    }
+                    b'\r' => {}
+                    _ => current.push(b),
+                }
+            }
+        }
+        i += 1;
+    }
+
+    if !current.is_empty() {
+        lines.push(Line {
+            number: start,
+            text: String::from_utf8_lossy(&current).into_owned(),
+        });
+    }
+    lines
+}
+
+fn is_identifier_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// Returns the identifier-like token directly preceding `bytes[i]`.
+fn preceding_token(bytes: &[u8], i: usize) -> &[u8] {
+    let mut start = i;
+    while start > 0 && is_identifier_byte(bytes[start - 1]) {
+        start -= 1;
+    }
+    &bytes[start..i]
+}
+
+/// Returns the identifier-like token starting at `bytes[i]`.
+fn following_token(bytes: &[u8], i: usize) -> &[u8] {
+    let len = bytes[i..]
+        .iter()
+        .position(|&b| !is_identifier_byte(b))
+        .unwrap_or(bytes.len() - i);
+    &bytes[i..i + len]
+}
+
+/// `'` inside a number like `1'000'000` is a digit separator, not a character literal.
+fn is_digit_separator(bytes: &[u8], i: usize) -> bool {
+    let token = preceding_token(bytes, i);
+    token.first().map(u8::is_ascii_digit).unwrap_or(false)
+        && bytes
+            .get(i + 1)
+            .map(|&b| is_identifier_byte(b))
+            .unwrap_or(false)
+}
+
+/// If the `"` at `bytes[i]` starts a raw string literal, returns the index after its end.
+fn raw_string_end(bytes: &[u8], i: usize) -> Option<usize> {
+    let prefix = preceding_token(bytes, i);
+    if !matches!(prefix, b"R" | b"u8R" | b"uR" | b"UR" | b"LR") {
+        return None;
+    }
+    let open = i + 1 + bytes[i + 1..].iter().position(|&b| b == b'(')?;
+    let delimiter = &bytes[i + 1..open];
+    if delimiter.len() > 16 || delimiter.iter().any(|b| b.is_ascii_whitespace()) {
+        return None;
+    }
+    let mut closing = Vec::with_capacity(delimiter.len() + 2);
+    closing.push(b')');
+    closing.extend_from_slice(delimiter);
+    closing.push(b'"');
+    let end = bytes[open..]
+        .windows(closing.len())
+        .position(|w| w == closing.as_slice())
+        .map(|p| open + p + closing.len())
+        .unwrap_or(bytes.len());
+    Some(end)
+}
+
+/// A preprocessor directive line, split into the directive name and the remaining text.
+pub struct DirectiveLine<'a> {
+    pub name: &'a str,
+    pub argument: &'a str,
+}
+
+pub fn parse_directive(line: &str) -> Option<DirectiveLine<'_>> {
+    let trimmed = line.trim_start();
+    let rest = trimmed.strip_prefix('#')?.trim_start();
+    let name = first_identifier(rest);
+    let argument = &rest[name.len()..];
+    Some(DirectiveLine {
+        name,
+        argument: argument.trim(),
+    })
+}
+
+/// The directives of a file that decide which of its includes are active.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Directive {
+    If(String),
+    Ifdef(String),
+    Ifndef(String),
+    Elif(String),
+    Else,
+    Endif,
+    Define {
+        name: String,
+        function_like: bool,
+        body: String,
+    },
+    Undef(String),
+    /// index into the includes of the file
+    Include(usize),
+}
+
+/// Tracks `#if`/`#elif`/`#else`/`#endif` nesting.
+///
+/// Conditions are three-valued: `None` means the scanner could not decide, in which case the
+/// branch is conservatively treated as active.
+#[derive(Default)]
+pub struct ConditionStack {
+    frames: Vec<ConditionFrame>,
+}
+
+struct ConditionFrame {
+    parent_active: bool,
+    active: bool,
+    // some branch of this group is known to be taken
+    taken: bool,
+}
+
+impl ConditionStack {
+    pub fn is_active(&self) -> bool {
+        self.frames.last().map(|f| f.active).unwrap_or(true)
+    }
+
+    pub fn push_if(&mut self, condition: Option<bool>) {
+        let parent_active = self.is_active();
+        self.frames.push(ConditionFrame {
+            parent_active,
+            active: parent_active && condition != Some(false),
+            taken: condition == Some(true),
+        });
+    }
+
+    pub fn elif(&mut self, condition: Option<bool>) {
+        if let Some(f) = self.frames.last_mut() {
+            f.active = f.parent_active && !f.taken && condition != Some(false);
+            f.taken |= condition == Some(true);
+        }
+    }
+
+    pub fn else_(&mut self) {
+        if let Some(f) = self.frames.last_mut() {
+            f.active = f.parent_active && !f.taken;
+            f.taken = true;
+        }
+    }
+
+    pub fn endif(&mut self) {
+        self.frames.pop();
+    }
+
+    /// Whether the enclosing group is active, i.e. whether `#elif` conditions need evaluating.
+    pub fn parent_active(&self) -> bool {
+        self.frames.last().map(|f| f.parent_active).unwrap_or(true)
+    }
+
+    /// Whether an `#elif` here may be taken: the enclosing group is active and no earlier branch
+    /// is known to be taken.
+    pub fn elif_reachable(&self) -> bool {
+        self.frames
+            .last()
+            .map(|f| f.parent_active && !f.taken)
+            .unwrap_or(true)
+    }
+}
+
+/// How the path of an include is delimited, which decides where it is searched.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IncludeKind {
+    /// `#include "path"`, searched next to the including file first
+    Quoted,
+    /// `#include <path>`, searched in the include paths only
+    Angle,
+    /// `#include MACRO` that could not be expanded, `path` holds the macro
+    Computed,
+}
+
+/// The directive that names a header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum IncludeDirective {
+    Include,
+    /// Objective-C `#import`, which includes a file only once
+    Import,
+    /// `#include_next`, which continues the search after the directory of the including file
+    IncludeNext,
+    /// `__has_include` or `__has_include_next` in a condition, which only probes for the file and
+    /// gives no link
+    HasInclude,
+    /// C++20 `import <header>;` or `import "header";`
+    HeaderUnit,
+}
+
+impl IncludeDirective {
+    /// How the directive is shown next to a link, `None` for a plain `#include`.
+    pub fn label(self) -> Option<&'static str> {
+        match self {
+            IncludeDirective::Include => None,
+            IncludeDirective::Import => Some("#import"),
+            IncludeDirective::IncludeNext => Some("#include_next"),
+            IncludeDirective::HasInclude => Some("__has_include"),
+            IncludeDirective::HeaderUnit => Some("header unit import"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Include {
+    /// the included path, with unix slashes
+    pub path: String,
+    pub kind: IncludeKind,
+    pub directive: IncludeDirective,
+    /// where the directive starts, both counting from 1
+    pub line: usize,
+    pub column: usize,
+    /// the argument as written, with its delimiters, or the macro of a computed include
+    pub spelling: String,
+}
+
+impl Include {
+    /// Whether the file is pulled in, as opposed to only being probed for.
+    pub fn includes_file(&self) -> bool {
+        self.directive != IncludeDirective::HasInclude
+    }
+
+    /// Whether the path comes from a macro, whether or not it could be expanded.
+    pub fn is_computed(&self) -> bool {
+        !self.spelling.starts_with(&['"', '<'][..])
+    }
+}
+
+/// A C++20 `module` declaration.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModuleDeclaration {
+    /// the module name, with the partition if any, as in "app.core:util"
+    pub name: String,
+    /// an interface unit (`export module`), as opposed to an implementation unit
+    pub exported: bool,
+}
+
+/// A C++20 `import` of a named module.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModuleImport {
+    /// the module name, with partitions qualified by their module
+    pub name: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+pub struct Scan {
+    pub includes: Vec<Include>,
+    pub directives: Vec<Directive>,
+    pub module: Option<ModuleDeclaration>,
+    pub imports: Vec<ModuleImport>,
+}
+
+/// Returns the includes and module imports in `text` that are not commented out or disabled by
+/// `#if 0`, together with the conditional directives that guard them.
+pub fn scan(text: &str) -> Scan {
+    let mut includes: Vec<Include> = vec![];
+    let mut directives = vec![];
+    let mut module: Option<ModuleDeclaration> = None;
+    let mut imports = vec![];
+    let mut conditions = ConditionStack::default();
+    let unknown = Macros::unknown();
+    // the object-like macros of this file, to expand computed includes
+    let mut local_macros = Macros::unknown();
+    for logical_line in logical_lines(text) {
+        let number = logical_line.number;
+        let column = logical_line.text.len() - logical_line.text.trim_start().len() + 1;
+        let line = match parse_directive(&logical_line.text) {
+            Some(d) => d,
+            None => {
+                if !conditions.is_active() {
+                    continue;
+                }
+                match parse_module_line(&logical_line.text) {
+                    Some(ModuleLine::Declaration { name, exported }) => {
+                        module = Some(ModuleDeclaration {
+                            name: name.to_string(),
+                            exported,
+                        });
+                    }
+                    Some(ModuleLine::Import(name)) => {
+                        let name = match (name.strip_prefix(':'), &module) {
+                            // a partition of the current module
+                            (Some(_), Some(module)) => {
+                                let primary = module.name.split(':').next().unwrap();
+                                format!("{}{}", primary, name)
+                            }
+                            _ => name.to_string(),
+                        };
+                        imports.push(ModuleImport {
+                            name,
+                            line: number,
+                            column,
+                        });
+                    }
+                    Some(ModuleLine::HeaderUnit(argument)) => {
+                        if let Some((path, kind)) = include_argument(argument) {
+                            directives.push(Directive::Include(includes.len()));
+                            includes.push(Include {
+                                path: path.to_string(),
+                                kind,
+                                directive: IncludeDirective::HeaderUnit,
+                                line: number,
+                                column,
+                                spelling: argument[..path.len() + 2].to_string(),
+                            });
+                        }
+                    }
+                    None => {}
+                }
+                continue;
+            }
+        };
+        let argument = line.argument;
+        // a probe counts if its condition is evaluated
+        let probes = match line.name {
+            "if" => conditions.is_active(),
+            "elif" => conditions.elif_reachable(),
+            _ => false,
+        };
+        if probes {
+            for (path, kind, spelling) in has_include_arguments(argument) {
+                includes.push(Include {
+                    path: path.to_string(),
+                    kind,
+                    directive: IncludeDirective::HasInclude,
+                    line: number,
+                    column,
+                    spelling: spelling.to_string(),
+                });
+            }
+        }
+        match line.name {
+            "if" => {
+                let condition = if conditions.is_active() {
+                    preprocessor::evaluate(argument, &unknown)
+                } else {
+                    None
+                };
+                conditions.push_if(condition);
+                directives.push(Directive::If(argument.to_string()));
+            }
+            "ifdef" | "ifndef" => {
+                conditions.push_if(None);
+                let name = first_identifier(argument).to_string();
+                directives.push(match line.name {
+                    "ifdef" => Directive::Ifdef(name),
+                    _ => Directive::Ifndef(name),
+                });
+            }
+            "elif" => {
+                let condition = if conditions.parent_active() {
+                    preprocessor::evaluate(argument, &unknown)
+                } else {
+                    None
+                };
+                conditions.elif(condition);
+                directives.push(Directive::Elif(argument.to_string()));
+            }
+            "else" => {
+                conditions.else_();
+                directives.push(Directive::Else);
+            }
+            "endif" => {
+                conditions.endif();
+                directives.push(Directive::Endif);
+            }
+            _ if !conditions.is_active() => {}
+            "define" => {
+                let name = first_identifier(argument);
+                let rest = &argument[name.len()..];
+                let function_like = rest.starts_with('(');
+                let body = if function_like {
+                    rest.find(')').map(|idx| &rest[idx + 1..]).unwrap_or("")
+                } else {
+                    rest
+                };
+                if function_like {
+                    local_macros.undefine(name);
+                } else {
+                    local_macros.define(name, body.trim());
+                }
+                directives.push(Directive::Define {
+                    name: name.to_string(),
+                    function_like,
+                    body: body.trim().to_string(),
+                });
+            }
+            "undef" => {
+                let name = first_identifier(argument);
+                local_macros.undefine(name);
+                directives.push(Directive::Undef(name.to_string()));
+            }
+            "include" | "import" | "include_next" => {
+                let directive = match line.name {
+                    "import" => IncludeDirective::Import,
+                    "include_next" => IncludeDirective::IncludeNext,
+                    _ => IncludeDirective::Include,
+                };
+                let (path, kind, spelling) = if let Some((path, kind)) = include_argument(argument)
+                {
+                    (path, kind, &argument[..path.len() + 2])
+                } else if !first_identifier(argument).is_empty() {
+                    // a computed include, resolved if the macro is defined in this file
+                    preprocessor::expand_include(argument, &local_macros)
+                        .and_then(include_argument)
+                        .map(|(path, kind)| (path, kind, argument))
+                        .unwrap_or((argument, IncludeKind::Computed, argument))
+                } else {
+                    continue;
+                };
+                directives.push(Directive::Include(includes.len()));
+                includes.push(Include {
+                    path: path.to_string(),
+                    kind,
+                    directive,
+                    line: number,
+                    column,
+                    spelling: spelling.to_string(),
+                });
+            }
+            _ => {}
+        }
+    }
+    Scan {
+        includes,
+        directives,
+        module,
+        imports,
+    }
+}
+
+/// A line of a C++20 module unit that declares or imports a module.
+enum ModuleLine<'a> {
+    Declaration {
+        name: &'a str,
+        exported: bool,
+    },
+    /// a named module, or a partition starting with `:`
+    Import(&'a str),
+    /// the `"path"` or `<path>` argument of a header unit import
+    HeaderUnit(&'a str),
+}
+
+/// Recognizes `export module a.b;`, `module a.b:part;`, `import a.b;`, `export import :part;`
+/// and `import <header>;`. The global module fragment (`module;`) and `module :private;` are
+/// ignored.
+fn parse_module_line(text: &str) -> Option<ModuleLine<'_>> {
+    let mut rest = text.trim_start();
+    let exported = first_identifier(rest) == "export";
+    if exported {
+        rest = rest["export".len()..].trim_start();
+    }
+    let keyword = first_identifier(rest);
+    let rest = rest[keyword.len()..].trim_start();
+    let argument = rest[..rest.find(';')?].trim();
+    let is_name = |name: &str| {
+        !name.is_empty()
+            && !name.starts_with('.')
+            && name
+                .bytes()
+                .all(|b| is_identifier_byte(b) || b == b'.' || b == b':')
+    };
+    match keyword {
+        "module" if is_name(argument) && !argument.starts_with(':') => {
+            Some(ModuleLine::Declaration {
+                name: argument,
+                exported,
+            })
+        }
+        "import" if argument.starts_with(&['<', '"'][..]) => Some(ModuleLine::HeaderUnit(argument)),
+        "import" if is_name(argument) => Some(ModuleLine::Import(argument)),
+        _ => None,
+    }
+}
+
+/// Finds the arguments of `__has_include(...)` and `__has_include_next(...)` in a condition,
+/// with their spelling.
+fn has_include_arguments(condition: &str) -> Vec<(&str, IncludeKind, &str)> {
+    let mut result = vec![];
+    let mut rest = condition;
+    while let Some(idx) = rest.find("__has_include") {
+        rest = &rest[idx + "__has_include".len()..];
+        rest = rest.strip_prefix("_next").unwrap_or(rest);
+        let argument = match rest.trim_start().strip_prefix('(') {
+            Some(argument) => argument.trim_start(),
+            None => continue,
+        };
+        if let Some((path, kind)) = include_argument(argument) {
+            result.push((path, kind, &argument[..path.len() + 2]));
+        }
+    }
+    result
+}
+
+fn first_identifier(text: &str) -> &str {
+    let len = text
+        .bytes()
+        .position(|b| !is_identifier_byte(b))
+        .unwrap_or(text.len());
+    &text[..len]
+}
+
+/// Extracts the path from a `"path"` or `<path>` include argument.
+pub fn include_argument(argument: &str) -> Option<(&str, IncludeKind)> {
+    let (closing, kind) = match argument.as_bytes().first()? {
+        b'"' => ('"', IncludeKind::Quoted),
+        b'<' => ('>', IncludeKind::Angle),
+        _ => return None,
+    };
+    let rest = &argument[1..];
+    let end = rest.find(closing)?;
+    if end == 0 {
+        return None;
+    }
+    Some((&rest[..end], kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// the path, line and column of each include
+    type Locations = &'static [(&'static str, usize, usize)];
+
+    /// the path, kind and directive of each include
+    type Kinds = &'static [(&'static str, IncludeKind, IncludeDirective)];
+
+    #[test]
+    fn split_logical_lines() {
+        let cases: &[(&str, &[(usize, &str)])] = &[
+            // source, (line number, text without trailing blanks) of each logical line
+            ("a\nb\n", &[(1, "a"), (2, "b")]),
+            ("a\r\nb", &[(1, "a"), (2, "b")]),
+            ("#define A \\\n  1\nb", &[(1, "#define A   1"), (3, "b")]),
+            ("#define A \\\r\n  1", &[(1, "#define A   1")]),
+            ("#inc\\\nlude <a.h>", &[(1, "#include <a.h>")]),
+            ("a // comment\nb", &[(1, "a"), (2, "b")]),
+            ("// comment \\\n#include <a.h>\nb", &[(1, ""), (3, "b")]),
+            ("a /* x */ b", &[(1, "a         b")]),
+            ("/*\n#include <a.h>\n*/ b", &[(3, "   b")]),
+            (
+                "a /*\n#include <a.h>\n*/ b",
+                &[(1, "a                    b")],
+            ),
+            (
+                "x = \"// not a comment\";",
+                &[(1, "x = \"                \";")],
+            ),
+            ("x = \"/* */\"; // c", &[(1, "x = \"     \";")]),
+            ("x = '\"'; y", &[(1, "x = ' '; y")]),
+            ("x = \"a\\\"b\";", &[(1, "x = \"    \";")]),
+            ("x = 1'000'000; y", &[(1, "x = 1'000'000; y")]),
+            (
+                "x = R\"(\n#include <a.h>\n)\";\nb",
+                &[(1, "x = R\"                \";"), (4, "b")],
+            ),
+            ("x = R\"d()\")d\"; y", &[(1, "x = R\"      \"; y")]),
+            ("#include \"a//b.h\"", &[(1, "#include \"a//b.h\"")]),
+            ("import \"a.h\";", &[(1, "import \"a.h\";")]),
+        ];
+        for &(source, expected) in cases {
+            let lines: Vec<(usize, String)> = logical_lines(source)
+                .into_iter()
+                .map(|line| (line.number, line.text.trim_end().to_string()))
+                .collect();
+            let expected: Vec<(usize, String)> = expected
+                .iter()
+                .map(|&(number, text)| (number, text.to_string()))
+                .collect();
+            assert_eq!(lines, expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn scan_includes() {
+        let cases: &[(&str, Locations)] = &[
+            (
+                "#include <a.h>\n#include \"b.h\"",
+                &[("a.h", 1, 1), ("b.h", 2, 1)],
+            ),
+            ("  #  include  <a.h>", &[("a.h", 1, 3)]),
+            ("#\tinclude\t\"a.h\"", &[("a.h", 1, 1)]),
+            ("// #include <a.h>", &[]),
+            ("/* #include <a.h> */", &[]),
+            ("/*\n#include <a.h>\n*/\n#include <b.h>", &[("b.h", 4, 1)]),
+            ("x = \"#include <a.h>\";", &[]),
+            ("x = R\"(\n#include <a.h>\n)\";", &[]),
+            (
+                "#if 0\n#include <a.h>\n#else\n#include <b.h>\n#endif",
+                &[("b.h", 4, 1)],
+            ),
+            (
+                "#if 1\n#include <a.h>\n#else\n#include <b.h>\n#endif",
+                &[("a.h", 2, 1)],
+            ),
+            (
+                "#ifdef A\n#include <a.h>\n#else\n#include <b.h>\n#endif",
+                &[("a.h", 2, 1), ("b.h", 4, 1)],
+            ),
+            (
+                "#include \\\n  <a.h>\n#include <b.h>",
+                &[("a.h", 1, 1), ("b.h", 3, 1)],
+            ),
+            ("#include <a.h> // <b.h>", &[("a.h", 1, 1)]),
+            ("#include <>", &[]),
+        ];
+        for &(source, expected) in cases {
+            let scan = scan(source);
+            let includes: Vec<(&str, usize, usize)> = scan
+                .includes
+                .iter()
+                .map(|i| (i.path.as_str(), i.line, i.column))
+                .collect();
+            assert_eq!(includes, expected, "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn scan_directive_kinds() {
+        use IncludeDirective::*;
+        use IncludeKind::*;
+        let cases: &[(&str, Kinds)] = &[
+            ("#include <a.h>", &[("a.h", Angle, Include)]),
+            ("#import \"a.h\"", &[("a.h", Quoted, Import)]),
+            ("#include_next <a.h>", &[("a.h", Angle, IncludeNext)]),
+            ("import <a.h>;", &[("a.h", Angle, HeaderUnit)]),
+            ("export import \"a.h\";", &[("a.h", Quoted, HeaderUnit)]),
+            ("#define H <a.h>\n#include H", &[("a.h", Angle, Include)]),
+            ("#include H", &[("H", Computed, Include)]),
+            (
+                "#if __has_include(<a.h>)\n#endif",
+                &[("a.h", Angle, HasInclude)],
+            ),
+            (
+                "#if __has_include_next( \"a.h\" ) && __has_include(<b.h>)\n#endif",
+                &[("a.h", Quoted, HasInclude), ("b.h", Angle, HasInclude)],
+            ),
+            ("#if 0\n#if __has_include(<a.h>)\n#endif\n#endif", &[]),
+            (
+                "#if 0\n#elif __has_include(<a.h>)\n#endif",
+                &[("a.h", Angle, HasInclude)],
+            ),
+            ("#if 1\n#elif __has_include(<a.h>)\n#endif", &[]),
+            (
+                "#ifdef A\n#elif __has_include(<a.h>)\n#endif",
+                &[("a.h", Angle, HasInclude)],
+            ),
+            (
+                "#if 0\n#if 1\n#elif __has_include(<a.h>)\n#endif\n#endif",
+                &[],
+            ),
+        ];
+        for &(source, expected) in cases {
+            let scan = scan(source);
+            let includes: Vec<(&str, IncludeKind, IncludeDirective)> = scan
+                .includes
+                .iter()
+                .map(|i| (i.path.as_str(), i.kind, i.directive))
+                .collect();
+            assert_eq!(includes, expected, "{:?}", source);
+        }
+    }
+}
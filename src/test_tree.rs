@@ -0,0 +1,42 @@
+//! Trees of files for tests that read from disk.
+
+use std::path::PathBuf;
+
+/// Files in a fresh directory below the temporary directory, removed again on drop.
+pub struct TestTree(PathBuf);
+
+impl TestTree {
+    /// Writes the files, given by relative path and contents. `name` keeps the trees of tests
+    /// that run in parallel apart.
+    pub fn new(name: &str, files: &[(&str, &[u8])]) -> TestTree {
+        let root = std::env::temp_dir().join(format!("cpdep-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let tree = TestTree(root);
+        for (path, contents) in files {
+            tree.write(path, contents);
+        }
+        tree
+    }
+
+    /// Writes a file, with the directories it is in.
+    pub fn write(&self, relative: &str, contents: &[u8]) {
+        let path = self.0.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    pub fn root(&self) -> &str {
+        self.0.to_str().unwrap()
+    }
+
+    pub fn path(&self, relative: &str) -> PathBuf {
+        self.0.join(relative)
+    }
+}
+
+impl Drop for TestTree {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -1,18 +1,20 @@
-use crate::graph::{ComponentRef, Edge, Graph};
+use cpdep::error::{Error, Result};
+use cpdep::graph::{ComponentRef, Edge, Graph, LoadOptions};
+use cpdep::{Change, Watcher};
 use crossterm::{
     event::{self, Event as CEvent, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use std::collections::HashMap;
-use std::io::{stdout, Write};
+use std::collections::{HashSet, VecDeque};
+use std::io::{self, stdout, Stdout, Write};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tui::backend::CrosstermBackend;
 use tui::layout::{Constraint, Direction, Layout};
 use tui::style::{Color, Style};
-use tui::widgets::{Block, Borders, List, ListItem, ListState};
+use tui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
 use tui::Terminal;
 
 struct Gui {
@@ -21,6 +23,12 @@ struct Gui {
     columns: [Column; 3],
     show_incoming_links: bool,
     show_only_public: bool,
+    /// the components that changed in the last refresh with `--watch`
+    changed: HashSet<ComponentRef>,
+    /// why the last refresh failed, if it did
+    refresh_error: Option<String>,
+    /// below the columns with `--watch`: the step of a running refresh, or why watching stopped
+    status: Option<String>,
 }
 
 impl Gui {
@@ -73,44 +81,106 @@ impl Column {
 
 enum Event<I> {
     Input(I),
+    /// files changed that the graph is loaded from
+    Changed(Change),
+    /// a step of reloading the graph
+    Progress(String),
+    /// the watcher failed, no more changes will come
+    WatchFailed(String),
 }
 
-pub fn show_ui(project: &Graph) -> Result<(), failure::Error> {
-    let project_names: Vec<&str> = project.components.iter().map(|c| c.nice_name()).collect();
-    let mut sorted_projects: Vec<(usize, &str)> = project_names
+fn sorted_components(project: &Graph) -> Vec<(ComponentRef, String)> {
+    let mut sorted_projects: Vec<(ComponentRef, String)> = project
+        .components
         .iter()
         .enumerate()
-        .map(|(i, s)| (i, *s))
+        .map(|(i, c)| (i, c.nice_name().to_string()))
         .collect();
-    sorted_projects.sort_by(|a, b| a.1.cmp(b.1));
-    let sorted_project_names: Vec<String> =
-        sorted_projects.iter().map(|(_i, s)| (*s).into()).collect();
+    sorted_projects.sort_by(|a, b| a.1.cmp(&b.1));
+    sorted_projects
+}
+
+fn component_items(
+    sorted_projects: &[(ComponentRef, String)],
+    changed: &HashSet<ComponentRef>,
+) -> Vec<String> {
+    sorted_projects
+        .iter()
+        .map(|(c, name)| match changed.contains(c) {
+            true => format!("* {}", name),
+            false => format!("  {}", name),
+        })
+        .collect()
+}
 
-    enable_raw_mode()?;
+/// crossterm and tui report errors in different types.
+fn terminal_error<E: std::error::Error + Send + Sync + 'static>(e: E) -> Error {
+    Error::Terminal(io::Error::other(e))
+}
+
+/// Shows the graph. With `watch`, reloads it with these options as files change.
+pub fn show_ui(project: &mut Graph, watch: Option<&LoadOptions>) -> Result<()> {
+    let mut sorted_projects = sorted_components(project);
+    let changed = HashSet::new();
+    let sorted_project_names = component_items(&sorted_projects, &changed);
+
+    let watcher = match watch {
+        Some(options) => Some(Watcher::new(options, &project.profile)?),
+        None => None,
+    };
+
+    enable_raw_mode().map_err(terminal_error)?;
 
     let mut stdout = stdout();
-    execute!(stdout, EnterAlternateScreen)?;
+    execute!(stdout, EnterAlternateScreen).map_err(terminal_error)?;
 
     let backend = CrosstermBackend::new(stdout);
 
-    let mut terminal = Terminal::new(backend)?;
-    terminal.hide_cursor()?;
+    let mut terminal = Terminal::new(backend).map_err(Error::Terminal)?;
+    terminal.hide_cursor().map_err(Error::Terminal)?;
 
     // Setup input handling
     let (tx, rx) = mpsc::channel();
 
+    if let Some(mut watcher) = watcher {
+        let tx = tx.clone();
+        thread::spawn(move || loop {
+            let event = match watcher.wait() {
+                Ok(change) => Event::Changed(change),
+                Err(e) => {
+                    let _ = tx.send(Event::WatchFailed(e.to_string()));
+                    break;
+                }
+            };
+            if tx.send(event).is_err() {
+                break;
+            }
+        });
+    }
+
+    // printing the steps of a refresh would garble the screen, they go to the status line
+    let watch = watch.map(|options| {
+        let tx = tx.clone();
+        options.clone().progress(move |message| {
+            let _ = tx.send(Event::Progress(message.to_string()));
+        })
+    });
+
     thread::spawn(move || {
-        loop {
-            // poll for tick rate duration, if no events, sent tick event.
-            if event::poll(Duration::from_millis(250)).unwrap() {
-                if let CEvent::Key(key) = event::read().unwrap() {
-                    tx.send(Event::Input(key)).unwrap();
+        // poll for tick rate duration, if no events, sent tick event.
+        // Ends when the terminal fails or the UI is gone.
+        while let Ok(ready) = event::poll(Duration::from_millis(250)) {
+            if ready {
+                match event::read() {
+                    Ok(CEvent::Key(key)) if tx.send(Event::Input(key)).is_err() => break,
+                    Err(_) => break,
+                    _ => {}
                 }
             }
         }
     });
 
-    terminal.clear()?;
+    terminal.clear().map_err(Error::Terminal)?;
 
     let mut gui = Gui {
         invalid: true,
@@ -122,85 +192,88 @@ pub fn show_ui(project: &Graph) -> Result<(), failure::Error> {
         ],
         show_incoming_links: true,
         show_only_public: false,
+        changed,
+        refresh_error: None,
+        status: None,
     };
+    // input that came in while the graph reloaded
+    let mut deferred = VecDeque::new();
 
     loop {
         if gui.invalid {
-            let (dep_in, dep_out) = project.linked_components(
+            let links = project.component_links(
                 sorted_projects[gui.columns[0].list_state.selected().unwrap_or(0)].0,
                 gui.show_only_public,
             );
 
             let (deps, files) = if gui.show_incoming_links {
-                get_dependencies_and_edge_descriptions(&project, dep_in)
+                get_dependencies_and_edge_descriptions(project, &links.incoming)
             } else {
-                get_dependencies_and_edge_descriptions(&project, dep_out)
+                get_dependencies_and_edge_descriptions(project, &links.outgoing)
             };
 
             gui.columns[1].items = deps;
             gui.columns[2].items = files
                 .into_iter()
                 .nth(gui.columns[1].list_state.selected().unwrap_or(0))
-                .take()
                 .unwrap_or_default();
         }
 
-        let mut field_heights = [0, 0, 0];
-
-        terminal.draw(|f| {
-            let vertical_split = Layout::default()
-                .direction(Direction::Vertical)
-                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-                .split(f.size());
-            let horizontal_split = Layout::default()
-                .direction(Direction::Horizontal)
-                .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
-                .split(vertical_split[0]);
-
-            let column_rects = [horizontal_split[0], horizontal_split[1], vertical_split[1]];
-            field_heights = [
-                column_rects[0].height,
-                column_rects[1].height,
-                column_rects[2].height,
-            ];
-
-            let style = Style::default();
-            let style_selected = Style::default().fg(Color::White).bg(Color::DarkGray);
-
-            for i in 0..3 {
-                let title = match i {
-                    0 => "Component (navigate with arrow/page keys)",
-                    1 if gui.show_incoming_links => "Incoming (press o for outgoing)",
-                    1 => "Outgoing (press i for incoming)",
-                    2 if gui.show_only_public => "Files (showing public references, toggle with p)",
-                    2 => "Files (showing all references, toggle with p)",
-                    _ => unreachable!(),
+        let field_heights = draw(&mut terminal, &mut gui, watch.is_some())?;
+
+        // the input thread only ends when the terminal fails
+        let event = match deferred.pop_front().map(Ok).unwrap_or_else(|| rx.recv()) {
+            Ok(event) => event,
+            Err(_) => break,
+        };
+        match event {
+            Event::Changed(change) => {
+                let options = match &watch {
+                    Some(options) => options,
+                    None => continue,
                 };
-                gui.make_valid();
-                let items: Vec<_> = gui.columns[i]
-                    .items
+                let selected = gui.columns[0].list_state.selected().unwrap_or(0);
+                let selected_name = sorted_projects.get(selected).map(|(_, name)| name.clone());
+                let reloaded = thread::scope(|scope| {
+                    let reload = scope.spawn(|| match change {
+                        Change::Files => project.reload(options),
+                        Change::Overflow => project.reload_all(options),
+                    });
+                    // keep drawing while the graph reloads
+                    while !reload.is_finished() {
+                        match rx.recv_timeout(Duration::from_millis(100)) {
+                            Ok(Event::Progress(message)) => {
+                                gui.status = Some(message);
+                                draw(&mut terminal, &mut gui, true)?;
+                            }
+                            Ok(event) => deferred.push_back(event),
+                            Err(_) => {}
+                        }
+                    }
+                    let reloaded = reload.join();
+                    Ok(reloaded.unwrap_or_else(|panic| std::panic::resume_unwind(panic)))
+                })?;
+                gui.status = None;
+                match reloaded {
+                    Ok(changed) => {
+                        gui.changed = changed.into_iter().collect();
+                        gui.refresh_error = None;
+                    }
+                    Err(e) => gui.refresh_error = Some(e.to_string()),
+                }
+                sorted_projects = sorted_components(project);
+                gui.columns[0].items = component_items(&sorted_projects, &gui.changed);
+                let selected = sorted_projects
                     .iter()
-                    .map(|i| ListItem::new(i.as_str()))
-                    .collect();
-                let list = List::new(items)
-                    .block(Block::default().borders(Borders::ALL).title(title))
-                    .highlight_symbol(">");
-                let list = match gui.sel_column == i {
-                    true => list.style(style).highlight_style(style_selected),
-                    false => list.style(style).highlight_style(style),
-                };
-                f.render_stateful_widget(list, column_rects[i], &mut gui.columns[i].list_state);
+                    .position(|(_, name)| Some(name) == selected_name.as_ref());
+                gui.columns[0].list_state.select(selected);
+                gui.invalid = true;
             }
-        })?;
-
-        match rx.recv()? {
+            // only drawn while a refresh runs
+            Event::Progress(_) => {}
+            Event::WatchFailed(e) => gui.status = Some(format!("watching stopped: {}", e)),
             Event::Input(event) => match event.code {
-                KeyCode::Char('c') if event.modifiers == KeyModifiers::CONTROL => {
-                    disable_raw_mode()?;
-                    execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
-                    terminal.show_cursor()?;
-                    break;
-                }
+                KeyCode::Char('c') if event.modifiers == KeyModifiers::CONTROL => break,
                 KeyCode::Char('i') => {
                     gui.columns[1].list_state.select(Some(0));
                     gui.show_incoming_links = true;
@@ -229,48 +302,115 @@ pub fn show_ui(project: &Graph) -> Result<(), failure::Error> {
                         gui.on_down();
                     }
                 }
-                KeyCode::Left => {
-                    if gui.sel_column > 0 {
-                        gui.sel_column -= 1;
-                    }
+                KeyCode::Left if gui.sel_column > 0 => {
+                    gui.sel_column -= 1;
                 }
-                KeyCode::Right => {
-                    if gui.sel_column < 2 {
-                        gui.sel_column += 1;
-                    }
+                KeyCode::Right if gui.sel_column < 2 => {
+                    gui.sel_column += 1;
                 }
                 _ => {}
             },
         }
     }
 
-    Ok(())
+    disable_raw_mode().map_err(terminal_error)?;
+    execute!(terminal.backend_mut(), LeaveAlternateScreen).map_err(terminal_error)?;
+    terminal.show_cursor().map_err(Error::Terminal)
+}
+
+/// Draws the columns, and with `watch` the status line below them. Returns the heights of the
+/// columns.
+fn draw(
+    terminal: &mut Terminal<CrosstermBackend<Stdout>>,
+    gui: &mut Gui,
+    watch: bool,
+) -> Result<[u16; 3]> {
+    let mut field_heights = [0, 0, 0];
+
+    let drawn = terminal.draw(|f| {
+        let status_height = if watch { 1 } else { 0 };
+        let status_split = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(0), Constraint::Length(status_height)].as_ref())
+            .split(f.size());
+        let vertical_split = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+            .split(status_split[0]);
+        let horizontal_split = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)].as_ref())
+            .split(vertical_split[0]);
+
+        let column_rects = [horizontal_split[0], horizontal_split[1], vertical_split[1]];
+        field_heights = [
+            column_rects[0].height,
+            column_rects[1].height,
+            column_rects[2].height,
+        ];
+
+        let style = Style::default();
+        let style_selected = Style::default().fg(Color::White).bg(Color::DarkGray);
+
+        let component_title = match (watch, &gui.refresh_error) {
+            (true, Some(e)) => format!("Component (refresh failed: {})", e),
+            (true, None) => format!(
+                "Component ({} changed in the last refresh, marked *)",
+                gui.changed.len()
+            ),
+            (false, _) => "Component (navigate with arrow/page keys)".to_string(),
+        };
+        for (i, &rect) in column_rects.iter().enumerate() {
+            let title = match i {
+                0 => component_title.as_str(),
+                1 if gui.show_incoming_links => "Incoming (press o for outgoing)",
+                1 => "Outgoing (press i for incoming)",
+                2 if gui.show_only_public => "Files (showing public references, toggle with p)",
+                2 => "Files (showing all references, toggle with p)",
+                _ => unreachable!(),
+            };
+            gui.make_valid();
+            let items: Vec<_> = gui.columns[i]
+                .items
+                .iter()
+                .map(|i| ListItem::new(i.as_str()))
+                .collect();
+            let list = List::new(items)
+                .block(Block::default().borders(Borders::ALL).title(title))
+                .highlight_symbol(">");
+            let list = match gui.sel_column == i {
+                true => list.style(style).highlight_style(style_selected),
+                false => list.style(style).highlight_style(style),
+            };
+            f.render_stateful_widget(list, rect, &mut gui.columns[i].list_state);
+        }
+        if watch {
+            let status = gui.status.as_deref().unwrap_or("watching for changes");
+            f.render_widget(Paragraph::new(status), status_split[1]);
+        }
+    });
+    drawn.map_err(Error::Terminal)?;
+    Ok(field_heights)
 }
 
 fn get_dependencies_and_edge_descriptions(
     project: &Graph,
-    deps: HashMap<ComponentRef, Vec<Edge>>,
+    deps: &[(ComponentRef, Vec<Edge>)],
 ) -> (Vec<String>, Vec<Vec<String>>) {
-    let mut sorted_keys: Vec<ComponentRef> = deps.keys().copied().collect();
-    let sort_fn = |a: &ComponentRef, b: &ComponentRef| {
-        project.components[*a]
-            .path
-            .cmp(&project.components[*b].path)
-    };
-    sorted_keys.sort_by(sort_fn);
-    let dep_names = sorted_keys
+    let dep_names = deps
         .iter()
-        .map(|&c_ref| project.components[c_ref].nice_name().into())
+        .map(|(c_ref, _)| project.components[*c_ref].nice_name().into())
         .collect();
-    let files = sorted_keys
-        .into_iter()
-        .map(|c_ref| {
-            deps[&c_ref]
+    let files = deps
+        .iter()
+        .map(|(_, edges)| {
+            edges
                 .iter()
                 .map(|e| {
                     format!(
                         "{} -> {}",
-                        project.files[e.from].path, project.files[e.to].path
+                        project.edge_location(e),
+                        project.files[e.to].path
                     )
                 })
                 .collect()
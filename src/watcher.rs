@@ -0,0 +1,276 @@
+//! Watching of the root for changes with inotify, to reload the graph as files change. The build
+//! files the graph is read from, like compile_commands.json, are watched as well.
+
+use crate::component_detector::ComponentDetector;
+use crate::error::{self, Context};
+use crate::graph::{LinkSource, LoadOptions};
+use crate::language::LanguageProfile;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What `Watcher::wait` woke up for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Change {
+    /// files changed that can change the graph
+    Files,
+    /// the kernel dropped events, so any file may have changed
+    Overflow,
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+pub struct Watcher {
+    root: PathBuf,
+    fd: i32,
+    /// the directory of each watch descriptor
+    directories: HashMap<i32, PathBuf>,
+    filter: Filter,
+    /// whether events were dropped since the last wake-up
+    overflowed: bool,
+}
+
+/// Tells the changes that can change the graph from those that cannot, like build outputs.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+struct Filter {
+    profile: LanguageProfile,
+    detectors: Vec<ComponentDetector>,
+    /// build files the graph is read from, canonicalized, like the component manifest
+    build_files: Vec<PathBuf>,
+    /// build directories the graph is read from, canonicalized, with the extension of the files
+    /// in them that matter
+    build_directories: Vec<(PathBuf, Option<&'static str>)>,
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+impl Filter {
+    fn new(options: &LoadOptions, profile: &LanguageProfile) -> Filter {
+        let canonical = |path: &Path| path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let mut build_files = vec![];
+        let mut build_directories = vec![];
+        match &options.links {
+            LinkSource::Includes => {}
+            LinkSource::CompileCommands(path) | LinkSource::IncludeLog(path) => {
+                build_files.push(canonical(Path::new(path)));
+            }
+            LinkSource::NinjaDeps(dir) => {
+                build_files.push(canonical(Path::new(dir)).join(".ninja_deps"));
+            }
+            LinkSource::Depfiles(dir) => {
+                build_directories.push((canonical(Path::new(dir)), Some("d")));
+            }
+            LinkSource::CMakeReply(dir) => {
+                let reply_dir = Path::new(dir).join(".cmake/api/v1/reply");
+                build_directories.push((canonical(&reply_dir), None));
+            }
+        }
+        if let Some(path) = &options.component_manifest {
+            build_files.push(canonical(Path::new(path)));
+        }
+        Filter {
+            profile: profile.clone(),
+            detectors: options.component_detectors.clone(),
+            build_files,
+            build_directories,
+        }
+    }
+
+    fn affects_graph(&self, path: &Path) -> bool {
+        let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        self.profile.file_kind(&path.to_string_lossy()).is_some()
+            || self.detectors.iter().any(|d| d.is_marker(name))
+            || self.build_files.iter().any(|f| f == path)
+            || self.build_directories.iter().any(|(directory, extension)| {
+                path.starts_with(directory)
+                    && extension.is_none_or(|e| path.extension() == Some(e.as_ref()))
+            })
+    }
+
+    fn in_build_directory(&self, path: &Path) -> bool {
+        self.build_directories
+            .iter()
+            .any(|(d, _)| path.starts_with(d))
+    }
+}
+
+/// Changes that arrive within this time of each other are reported together.
+#[cfg(target_os = "linux")]
+const SETTLE_MILLISECONDS: i32 = 100;
+
+#[cfg(target_os = "linux")]
+impl Watcher {
+    /// Watches all directories below the root that the file collector would visit, and the build
+    /// files of the link source and the component manifest.
+    pub fn new(options: &LoadOptions, profile: &LanguageProfile) -> error::Result<Watcher> {
+        let root = PathBuf::from(&options.root);
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error()).context("watch", &root);
+        }
+        let mut watcher = Watcher {
+            root,
+            fd,
+            directories: HashMap::new(),
+            filter: Filter::new(options, profile),
+            overflowed: false,
+        };
+        let root = watcher.root.clone();
+        watcher.watch_all().context("watch", &root)?;
+        Ok(watcher)
+    }
+
+    /// Blocks until a file changes that can change the graph. Changes are collected until they
+    /// settle, so that saving many files at once gives one wake-up.
+    pub fn wait(&mut self) -> error::Result<Change> {
+        let root = self.root.clone();
+        self.wait_for_change().context("watch", &root)
+    }
+
+    fn wait_for_change(&mut self) -> io::Result<Change> {
+        loop {
+            let mut changed = self.read_events()?;
+            while self.poll(SETTLE_MILLISECONDS)? {
+                changed.extend(self.read_events()?);
+            }
+            if std::mem::take(&mut self.overflowed) {
+                // new directories may have been missed as well
+                self.watch_all()?;
+                return Ok(Change::Overflow);
+            }
+            if changed.iter().any(|path| self.filter.affects_graph(path)) {
+                return Ok(Change::Files);
+            }
+        }
+    }
+
+    /// Watches the tree, then the build files. A directory that is both is known by its
+    /// canonical path, which the build files are compared with.
+    fn watch_all(&mut self) -> io::Result<()> {
+        let root = self.root.clone();
+        self.watch_tree(&root, true)?;
+        let parents: Vec<PathBuf> = self
+            .filter
+            .build_files
+            .iter()
+            .filter_map(|f| Some(f.parent()?.to_path_buf()))
+            .collect();
+        for parent in parents {
+            self.watch(&parent)?;
+        }
+        let directories: Vec<PathBuf> = self
+            .filter
+            .build_directories
+            .iter()
+            .map(|(d, _)| d.clone())
+            .collect();
+        for directory in directories {
+            self.watch_tree(&directory, false)?;
+        }
+        Ok(())
+    }
+
+    /// Watches the directories below `root`, skipping ignored ones with `standard_filters` as
+    /// the file collector does. Build directories are usually ignored, so they are not filtered.
+    fn watch_tree(&mut self, root: &Path, standard_filters: bool) -> io::Result<()> {
+        let directories = ignore::WalkBuilder::new(root)
+            .standard_filters(standard_filters)
+            .build()
+            .filter_map(Result::ok)
+            .filter(|e| e.file_type().map(|t| t.is_dir()).unwrap_or(false));
+        for directory in directories {
+            self.watch(directory.path())?;
+        }
+        Ok(())
+    }
+
+    fn watch(&mut self, directory: &Path) -> io::Result<()> {
+        use std::os::unix::ffi::OsStrExt;
+        let path = std::ffi::CString::new(directory.as_os_str().as_bytes())?;
+        let mask = libc::IN_CLOSE_WRITE
+            | libc::IN_CREATE
+            | libc::IN_DELETE
+            | libc::IN_MOVED_FROM
+            | libc::IN_MOVED_TO;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask) };
+        if wd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        self.directories.insert(wd, directory.to_path_buf());
+        Ok(())
+    }
+
+    fn poll(&self, timeout: i32) -> io::Result<bool> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        match unsafe { libc::poll(&mut poll_fd, 1, timeout) } {
+            n if n < 0 => Err(io::Error::last_os_error()),
+            n => Ok(n > 0),
+        }
+    }
+
+    /// Reads the pending events, blocking until there is at least one, and returns the paths
+    /// they are about. New directories are watched as well.
+    fn read_events(&mut self) -> io::Result<Vec<PathBuf>> {
+        let mut buffer = [0u8; 64 * 1024];
+        let length = unsafe { libc::read(self.fd, buffer.as_mut_ptr().cast(), buffer.len()) };
+        if length < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let header = std::mem::size_of::<libc::inotify_event>();
+        let mut paths = vec![];
+        let mut offset = 0;
+        while offset + header <= length as usize {
+            let event: libc::inotify_event =
+                unsafe { std::ptr::read_unaligned(buffer[offset..].as_ptr().cast()) };
+            let name = &buffer[offset + header..offset + header + event.len as usize];
+            offset += header + event.len as usize;
+
+            if event.mask & libc::IN_Q_OVERFLOW != 0 {
+                self.overflowed = true;
+                continue;
+            }
+            if event.mask & libc::IN_IGNORED != 0 {
+                self.directories.remove(&event.wd);
+                continue;
+            }
+            let directory = match self.directories.get(&event.wd) {
+                Some(directory) => directory,
+                None => continue,
+            };
+            // the name is padded with NULs
+            let name = name.split(|&b| b == 0).next().unwrap_or(&[]);
+            let path = directory.join(String::from_utf8_lossy(name).as_ref());
+            if event.mask & libc::IN_ISDIR != 0
+                && event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0
+            {
+                let standard_filters = !self.filter.in_build_directory(&path);
+                self.watch_tree(&path, standard_filters)?;
+            }
+            paths.push(path);
+        }
+        Ok(paths)
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Watcher {
+    pub fn new(options: &LoadOptions, _profile: &LanguageProfile) -> error::Result<Watcher> {
+        Err(io::Error::other(
+            "watching needs inotify, which is only available on Linux",
+        ))
+        .context("watch", &options.root)
+    }
+
+    pub fn wait(&mut self) -> error::Result<Change> {
+        unreachable!("no watcher is created on this platform")
+    }
+}
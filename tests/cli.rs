@@ -0,0 +1,33 @@
+//! Runs the binary and checks its exit codes.
+
+use std::process::Command;
+
+fn cpdep(args: &[&str]) -> Option<i32> {
+    let output = Command::new(env!("CARGO_BIN_EXE_cpdep"))
+        .args(args)
+        .output()
+        .unwrap();
+    output.status.code()
+}
+
+#[test]
+fn usage_errors_exit_with_2() {
+    // neither --root nor --import
+    assert_eq!(cpdep(&["scc"]), Some(2));
+    assert_eq!(cpdep(&["--root", ".", "--import", "graph", "scc"]), Some(2));
+    assert_eq!(
+        cpdep(&["--root", "src", "headers", "no such component"]),
+        Some(2)
+    );
+}
+
+#[test]
+fn input_errors_exit_with_3() {
+    assert_eq!(cpdep(&["--root", "no such directory", "scc"]), Some(3));
+    assert_eq!(cpdep(&["--import", "no such graph", "scc"]), Some(3));
+}
+
+#[test]
+fn help_exits_with_0() {
+    assert_eq!(cpdep(&["--help"]), Some(0));
+}